@@ -0,0 +1,94 @@
+use crate::profiling::performance_now;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::BTreeMap};
+
+thread_local! {
+    /// Running tasks, and whether they have been cancelled.
+    static RUNNING_TASKS: RefCell<BTreeMap<u32, bool>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Requests the running task `task_id` to stop. Does nothing if the task isn't running, either
+/// not started yet or already finished.
+pub fn cancel(task_id: u32) {
+    RUNNING_TASKS.with(|tasks| {
+        if let Some(cancelled) = tasks.borrow_mut().get_mut(&task_id) {
+            *cancelled = true;
+        }
+    });
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AbortReason {
+    Cancelled,
+    TimedOut,
+}
+
+/// Why and where a task stopped before finishing.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskAborted {
+    reason: AbortReason,
+    /// Runtime call that was running.
+    call: String,
+    /// Milliseconds since the task started.
+    elapsed: f64,
+    message: String,
+}
+
+/// Stops a task once its deadline has passed or it has been cancelled.
+///
+/// The runtime can only be stopped when it hands a host function over to the executor, and a
+/// cancellation is only received while the executor awaits `JsCallback`. A runtime spinning
+/// without calling the host can't be interrupted, smoldot doesn't expose instruction metering.
+pub struct TaskAbort {
+    task_id: u32,
+    start: f64,
+    timeout: Option<u64>,
+}
+
+impl TaskAbort {
+    pub fn new(task_id: u32, timeout: Option<u64>) -> Self {
+        RUNNING_TASKS.with(|tasks| tasks.borrow_mut().insert(task_id, false));
+        TaskAbort {
+            task_id,
+            start: performance_now(),
+            timeout,
+        }
+    }
+
+    pub fn check(&self, call: &str) -> Result<(), TaskAborted> {
+        let elapsed = performance_now() - self.start;
+        let aborted = |reason, message| TaskAborted {
+            reason,
+            call: call.to_string(),
+            elapsed,
+            message,
+        };
+        if RUNNING_TASKS.with(|tasks| tasks.borrow().get(&self.task_id) == Some(&true)) {
+            return Err(aborted(
+                AbortReason::Cancelled,
+                format!(
+                    "Task {} cancelled in {call} after {elapsed:.0} ms",
+                    self.task_id
+                ),
+            ));
+        }
+        match self.timeout {
+            Some(timeout) if elapsed > timeout as f64 => Err(aborted(
+                AbortReason::TimedOut,
+                format!(
+                    "Task {} timed out in {call} after {elapsed:.0} ms, timeout {timeout} ms",
+                    self.task_id
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for TaskAbort {
+    fn drop(&mut self) {
+        RUNNING_TASKS.with(|tasks| tasks.borrow_mut().remove(&self.task_id));
+    }
+}
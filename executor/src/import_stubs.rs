@@ -0,0 +1,456 @@
+use crate::proof::blake2_256;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, convert::Infallible, rc::Rc};
+use wasm_encoder::{
+    reencode::{self, utils, Reencode},
+    CodeSection, Function, FunctionSection, ImportSection, Instruction, Module, NameMap,
+    NameSection,
+};
+use wasmparser::{FuncType, Parser, Payload, TypeRef, ValType};
+
+/// Module the host functions of a Substrate runtime are imported from.
+const HOST_MODULE: &str = "env";
+
+/// Runtimes starting with this prefix are zstd-compressed, same as in smoldot.
+const ZSTD_PREFIX: [u8; 8] = [82, 188, 83, 118, 70, 219, 142, 5];
+
+/// Largest decompressed runtime accepted, same as in smoldot.
+const MAX_RUNTIME_SIZE: usize = 50 * 1024 * 1024;
+
+/// Number of rewritten runtimes kept by `stub_imports_cached`, a fork rarely runs more than two
+/// runtimes at once.
+const STUBBED_RUNTIMES_CACHE_SIZE: usize = 2;
+
+thread_local! {
+    /// Runtimes rewritten by `stub_imports_cached`, the most recently used last.
+    static STUBBED_RUNTIMES: RefCell<Vec<StubbedRuntime>> = const { RefCell::new(Vec::new()) };
+}
+
+struct StubbedRuntime {
+    code_hash: [u8; 32],
+    stubs: BTreeMap<String, ImportStub>,
+    wasm: Rc<[u8]>,
+}
+
+/// Built-in implementation of a host function that smoldot doesn't provide.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ImportStub {
+    /// Returns zero for every result of the function.
+    Zero,
+    /// Returns the value, the function must have a single `i32` or `i64` result.
+    Constant(i64),
+    /// Does nothing, the function must not have any result.
+    NoOp,
+}
+
+impl ImportStub {
+    fn body(self, name: &str, ty: &FuncType) -> Result<Function, String> {
+        let mut function = Function::new([]);
+        match (self, ty.results()) {
+            (ImportStub::Zero, results) => {
+                for result in results {
+                    function.instruction(&match result {
+                        ValType::I32 => Instruction::I32Const(0),
+                        ValType::I64 => Instruction::I64Const(0),
+                        ValType::F32 => Instruction::F32Const(0.0),
+                        ValType::F64 => Instruction::F64Const(0.0),
+                        ty => return Err(format!("Can't stub `{name}`, unsupported result {ty}")),
+                    });
+                }
+            }
+            (ImportStub::Constant(value), [ValType::I32]) => {
+                let value = i32::try_from(value).map_err(|_| {
+                    format!("Can't stub `{name}`, constant {value} doesn't fit its i32 result")
+                })?;
+                function.instruction(&Instruction::I32Const(value));
+            }
+            (ImportStub::Constant(value), [ValType::I64]) => {
+                function.instruction(&Instruction::I64Const(value));
+            }
+            (ImportStub::Constant(_), _) => {
+                return Err(format!(
+                    "Can't stub `{name}` with a constant, it must have a single i32 or i64 result"
+                ))
+            }
+            (ImportStub::NoOp, []) => {}
+            (ImportStub::NoOp, _) => {
+                return Err(format!(
+                    "Can't stub `{name}` with a no-op, it must not have any result"
+                ))
+            }
+        }
+        function.instruction(&Instruction::End);
+        Ok(function)
+    }
+}
+
+/// Replaces the host functions named in `stubs` that the runtime imports with local functions
+/// implementing the stub, so that runtimes importing host functions smoldot lacks can run.
+///
+/// Imported functions come first in the function index space, so every function following a
+/// stubbed import is renumbered, and the stubs are appended after the local functions. The
+/// `name` section follows the renumbering, the stubs are left unnamed.
+pub fn stub_imports<'a>(
+    wasm: &'a [u8],
+    stubs: &BTreeMap<String, ImportStub>,
+) -> Result<Cow<'a, [u8]>, String> {
+    if stubs.is_empty() {
+        return Ok(Cow::Borrowed(wasm));
+    }
+    let wasm = decompress(wasm)?;
+
+    let mut func_types = vec![];
+    let mut imported_functions = 0;
+    let mut local_functions = 0;
+    let mut has_code = false;
+    let mut stubbed = vec![];
+    let mut bodies = vec![];
+    for payload in Parser::new(0).parse_all(&wasm) {
+        match payload.map_err(|e| e.to_string())? {
+            Payload::TypeSection(section) => {
+                for ty in section.into_iter_err_on_gc_types() {
+                    func_types.push(ty.map_err(|e| e.to_string())?);
+                }
+            }
+            Payload::ImportSection(section) => {
+                for import in section {
+                    let import = import.map_err(|e| e.to_string())?;
+                    let TypeRef::Func(type_index) = import.ty else {
+                        continue;
+                    };
+                    let stub = match stubs.get(import.name) {
+                        Some(stub) if import.module == HOST_MODULE => stub,
+                        _ => {
+                            imported_functions += 1;
+                            continue;
+                        }
+                    };
+                    let ty = func_types
+                        .get(type_index as usize)
+                        .ok_or_else(|| format!("Invalid type of `{}`", import.name))?;
+                    bodies.push((type_index, stub.body(import.name, ty)?));
+                    stubbed.push(imported_functions);
+                    imported_functions += 1;
+                }
+            }
+            Payload::FunctionSection(section) => local_functions = section.count(),
+            Payload::CodeSectionStart { .. } => has_code = true,
+            _ => {}
+        }
+    }
+
+    if stubbed.is_empty() {
+        return Ok(wasm);
+    }
+    if !has_code {
+        return Err("Can't stub imports of a runtime without code section".to_string());
+    }
+
+    let mut module = Module::new();
+    ImportStubber {
+        imported_functions,
+        local_functions,
+        stubbed,
+        bodies,
+    }
+    .parse_core_module(&mut module, Parser::new(0), &wasm)
+    .map_err(|e| e.to_string())?;
+
+    Ok(Cow::Owned(module.finish()))
+}
+
+/// Same as `stub_imports`, keeping the last rewritten runtimes by code hash and stubs since
+/// decompressing and re-encoding a whole runtime on every call is wasted work. Returns `None` if
+/// there is nothing to stub.
+pub fn stub_imports_cached(
+    wasm: &[u8],
+    stubs: &BTreeMap<String, ImportStub>,
+) -> Result<Option<Rc<[u8]>>, String> {
+    if stubs.is_empty() {
+        return Ok(None);
+    }
+
+    let code_hash = blake2_256(wasm);
+    let cached = STUBBED_RUNTIMES.with(|runtimes| {
+        let mut runtimes = runtimes.borrow_mut();
+        let index = runtimes
+            .iter()
+            .position(|runtime| runtime.code_hash == code_hash && &runtime.stubs == stubs)?;
+        let runtime = runtimes.remove(index);
+        let wasm = runtime.wasm.clone();
+        runtimes.push(runtime);
+        Some(wasm)
+    });
+    if let Some(wasm) = cached {
+        return Ok(Some(wasm));
+    }
+
+    let wasm = Rc::<[u8]>::from(stub_imports(wasm, stubs)?);
+    STUBBED_RUNTIMES.with(|runtimes| {
+        let mut runtimes = runtimes.borrow_mut();
+        if runtimes.len() == STUBBED_RUNTIMES_CACHE_SIZE {
+            runtimes.remove(0);
+        }
+        runtimes.push(StubbedRuntime {
+            code_hash,
+            stubs: stubs.clone(),
+            wasm: wasm.clone(),
+        });
+    });
+    Ok(Some(wasm))
+}
+
+fn decompress(wasm: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    let Some(mut data) = wasm.strip_prefix(&ZSTD_PREFIX[..]) else {
+        return Ok(Cow::Borrowed(wasm));
+    };
+
+    let mut decoder = ruzstd::decoding::FrameDecoder::new();
+    decoder
+        .init(&mut data)
+        .map_err(|e| format!("Invalid zstd runtime: {e}"))?;
+    let finished = decoder
+        .decode_blocks(
+            &mut data,
+            ruzstd::decoding::BlockDecodingStrategy::UptoBytes(MAX_RUNTIME_SIZE),
+        )
+        .map_err(|e| format!("Invalid zstd runtime: {e}"))?;
+    if !finished {
+        return Err("Decompressed runtime is too large".to_string());
+    }
+
+    Ok(Cow::Owned(decoder.collect().unwrap_or_default()))
+}
+
+struct ImportStubber {
+    imported_functions: u32,
+    local_functions: u32,
+    /// Function indices of the stubbed imports, ascending.
+    stubbed: Vec<u32>,
+    /// Type index and body of the stubs, in the order of `stubbed`.
+    bodies: Vec<(u32, Function)>,
+}
+
+impl Reencode for ImportStubber {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        let stubbed = self.stubbed.len() as u32;
+        if func >= self.imported_functions {
+            return func - stubbed;
+        }
+        match self.stubbed.binary_search(&func) {
+            Ok(stub) => self.imported_functions - stubbed + self.local_functions + stub as u32,
+            Err(stubbed_before) => func - stubbed_before as u32,
+        }
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        let mut func = 0;
+        for import in section {
+            let import = import?;
+            if let TypeRef::Func(_) = import.ty {
+                func += 1;
+                if self.stubbed.binary_search(&(func - 1)).is_ok() {
+                    continue;
+                }
+            }
+            self.parse_import(imports, import)?;
+        }
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        utils::parse_function_section(self, functions, section)?;
+        for (type_index, _) in &self.bodies {
+            functions.function(*type_index);
+        }
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        utils::parse_code_section(self, code, section)?;
+        for (_, body) in &self.bodies {
+            code.function(body);
+        }
+        Ok(())
+    }
+
+    fn parse_custom_name_subsection(
+        &mut self,
+        names: &mut NameSection,
+        section: wasmparser::Name<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        let wasmparser::Name::Function(map) = section else {
+            return utils::parse_custom_name_subsection(self, names, section);
+        };
+        // stubs come after the local functions, naming them would break the ascending order
+        let mut functions = NameMap::new();
+        for naming in map {
+            let naming = naming?;
+            if self.stubbed.binary_search(&naming.index).is_err() {
+                functions.append(self.function_index(naming.index), naming.name);
+            }
+        }
+        names.functions(&functions);
+        Ok(())
+    }
+}
+
+#[test]
+fn stub_imports_works() {
+    use smoldot::executor::vm::{
+        Config, ExecHint, ExecOutcome, VirtualMachinePrototype, WasmValue,
+    };
+    use wasm_encoder::{EntityType, ExportKind, ExportSection, MemoryType, TypeSection, ValType};
+
+    // imports `ext_answer`, `ext_kept` and `ext_log`, `run` returns `double(ext_answer())` after
+    // calling `ext_log`
+    let mut types = TypeSection::new();
+    types.ty().function([], [ValType::I64]);
+    types.ty().function([ValType::I32], []);
+    types.ty().function([ValType::I64], [ValType::I64]);
+    let mut imports = ImportSection::new();
+    let memory = MemoryType {
+        minimum: 1,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    };
+    imports.import("env", "memory", EntityType::Memory(memory));
+    imports.import("env", "ext_answer", EntityType::Function(0));
+    imports.import("env", "ext_kept", EntityType::Function(1));
+    imports.import("env", "ext_log", EntityType::Function(1));
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+    functions.function(2);
+    let mut exports = ExportSection::new();
+    exports.export("run", ExportKind::Func, 3);
+    let mut code = CodeSection::new();
+    let mut run = Function::new([]);
+    run.instruction(&Instruction::I32Const(7));
+    run.instruction(&Instruction::Call(2));
+    run.instruction(&Instruction::Call(0));
+    run.instruction(&Instruction::Call(4));
+    run.instruction(&Instruction::End);
+    code.function(&run);
+    let mut double = Function::new([]);
+    double.instruction(&Instruction::LocalGet(0));
+    double.instruction(&Instruction::I64Const(2));
+    double.instruction(&Instruction::I64Mul);
+    double.instruction(&Instruction::End);
+    code.function(&double);
+    let mut module = Module::new();
+    module.section(&types);
+    module.section(&imports);
+    module.section(&functions);
+    module.section(&exports);
+    module.section(&code);
+    let mut function_names = NameMap::new();
+    for (index, name) in ["ext_answer", "ext_kept", "ext_log", "run", "double"]
+        .into_iter()
+        .enumerate()
+    {
+        function_names.append(index as u32, name);
+    }
+    let mut names = NameSection::new();
+    names.functions(&function_names);
+    module.section(&names);
+    let wasm = module.finish();
+
+    let function_names = |wasm: &[u8]| {
+        let mut function_names = vec![];
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let Payload::CustomSection(section) = payload.unwrap() {
+                if let wasmparser::KnownCustom::Name(names) = section.as_known() {
+                    for name in names {
+                        if let wasmparser::Name::Function(map) = name.unwrap() {
+                            for naming in map {
+                                let naming = naming.unwrap();
+                                function_names.push((naming.index, naming.name.to_string()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        function_names
+    };
+
+    let run = |wasm: &[u8]| {
+        let mut symbols = |_: &str, name: &str, _: &_| match name {
+            "ext_log" => Ok(0),
+            _ => Err(()),
+        };
+        let mut vm = VirtualMachinePrototype::new(Config {
+            module_bytes: wasm,
+            exec_hint: ExecHint::ValidateAndExecuteOnce,
+            symbols: &mut symbols,
+        })
+        .map_err(|e| e.to_string())?
+        .prepare()
+        .start("run", &[])
+        .map_err(|(e, _)| e.to_string())?;
+        loop {
+            match vm.run(None).map_err(|e| e.to_string())? {
+                ExecOutcome::Finished { return_value } => {
+                    return return_value.map_err(|e| e.to_string());
+                }
+                ExecOutcome::Interrupted { id, params } => {
+                    assert_eq!((id, params), (0, vec![WasmValue::I32(7)]));
+                }
+            }
+        }
+    };
+
+    // `ext_answer` and `ext_kept` can't be resolved
+    assert!(run(&wasm).is_err());
+
+    let stubs = BTreeMap::from([
+        ("ext_answer".to_string(), ImportStub::Constant(21)),
+        ("ext_kept".to_string(), ImportStub::NoOp),
+    ]);
+    let stubbed = stub_imports(&wasm, &stubs).unwrap();
+    assert_eq!(run(&stubbed), Ok(Some(WasmValue::I64(42))));
+    // the remaining functions keep their names, the stubs are unnamed
+    assert_eq!(
+        function_names(&stubbed),
+        vec![
+            (0, "ext_log".to_string()),
+            (1, "run".to_string()),
+            (2, "double".to_string())
+        ]
+    );
+
+    let cached = stub_imports_cached(&wasm, &stubs).unwrap().unwrap();
+    assert_eq!(&cached[..], &stubbed[..]);
+    let cached_again = stub_imports_cached(&wasm, &stubs).unwrap().unwrap();
+    assert!(Rc::ptr_eq(&cached, &cached_again));
+    assert!(stub_imports_cached(&wasm, &BTreeMap::new())
+        .unwrap()
+        .is_none());
+
+    let stubs = BTreeMap::from([
+        ("ext_answer".to_string(), ImportStub::Zero),
+        ("ext_kept".to_string(), ImportStub::NoOp),
+    ]);
+    let stubbed = stub_imports(&wasm, &stubs).unwrap();
+    assert_eq!(run(&stubbed), Ok(Some(WasmValue::I64(0))));
+
+    let stubs = BTreeMap::from([("ext_answer".to_string(), ImportStub::NoOp)]);
+    assert!(stub_imports(&wasm, &stubs).is_err());
+}
@@ -1,18 +1,85 @@
 extern crate console_error_panic_hook;
 
 use log::{Level, Log, Metadata, Record};
+use logging::LogDirectives;
+use serde::Serialize;
 use smoldot::{
     json_rpc::methods::{HashHexString, HexString},
-    trie::TrieEntryVersion,
+    trie::{HashFunction, TrieEntryVersion},
 };
-use std::collections::BTreeMap;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 
+mod cancel;
+mod import_stubs;
+mod logging;
+mod profiling;
 mod proof;
+mod signature;
+mod state_trie;
+mod storage_trace;
 mod task;
 
-static LOGGER: WebConsoleLogger = WebConsoleLogger {};
+static LOGGER: ExecutorLogger = ExecutorLogger {};
+
+thread_local! {
+    static LOG_SINK: RefCell<Option<Rc<LogSink>>> = const { RefCell::new(None) };
+}
+
+struct LogSink {
+    callback: js_sys::Function,
+    directives: Option<LogDirectives>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LogSinkRecord<'a> {
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    task_id: Option<u64>,
+}
+
+/// Sends records to the sink registered with `set_log_sink`, or to the web console if there is
+/// none.
+struct ExecutorLogger {}
+
+impl Log for ExecutorLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(sink) = LOG_SINK.with(|sink| sink.borrow().clone()) else {
+            return WebConsoleLogger {}.log(record);
+        };
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(directives) = &sink.directives {
+            if !directives.enabled(record.target(), record.level()) {
+                return;
+            }
+        }
+
+        let level = record.level().as_str().to_lowercase();
+        let record = LogSinkRecord {
+            level: &level,
+            target: record.target(),
+            message: record.args().to_string(),
+            task_id: record
+                .key_values()
+                .get(log::kv::Key::from_str("task_id"))
+                .and_then(|x| x.to_u64()),
+        };
+        if let Ok(record) = serde_wasm_bindgen::to_value(&record) {
+            let _ = sink.callback.call1(&JsValue::NULL, &record);
+        }
+    }
+
+    fn flush(&self) {}
+}
 
 struct WebConsoleLogger {}
 
@@ -54,6 +121,15 @@ fn setup_console(level: Option<log::Level>) {
     log::set_max_level(level.unwrap_or(log::Level::Info).to_level_filter());
 }
 
+/// Trie hash function, `0` for Blake2 (the default) and `1` for Keccak-256.
+fn hash_function(value: JsValue) -> Result<HashFunction, JsError> {
+    match serde_wasm_bindgen::from_value::<Option<u8>>(value)? {
+        None | Some(0) => Ok(HashFunction::Blake2),
+        Some(1) => Ok(HashFunction::Keccak256),
+        Some(_) => Err(JsError::new("invalid hash function")),
+    }
+}
+
 #[wasm_bindgen(typescript_custom_section)]
 const _: &'static str = r#"
 type HexString = `0x${string}`;
@@ -64,6 +140,7 @@ export interface JsCallback {
 	offchainTimestamp: () => Promise<number>
 	offchainRandomSeed: () => Promise<HexString>
 	offchainSubmitTransaction: (tx: HexString) => Promise<boolean>
+	onLog?: (event: { taskId: number; callIndex: number; log: { message: string; level?: number; target?: string } }) => Promise<void>
 }
 "#;
 
@@ -96,6 +173,36 @@ extern "C" {
         this: &JsCallback,
         tx: JsValue,
     ) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, structural, method, js_name = "onLog")]
+    pub async fn on_log(this: &JsCallback, event: JsValue) -> Result<JsValue, JsValue>;
+
+    /// `onLog` itself, to tell whether the optional callback is set.
+    #[wasm_bindgen(structural, method, getter, js_name = "onLog")]
+    pub fn on_log_callback(this: &JsCallback) -> JsValue;
+}
+
+/// Registers the function receiving executor logs as `{ level, target, message, taskId }`
+/// records, filtered by optional `RUST_LOG`-style directives. Logs go to the web console if no
+/// sink is registered.
+#[wasm_bindgen]
+pub async fn set_log_sink(
+    sink: Option<js_sys::Function>,
+    directives: JsValue,
+) -> Result<(), JsError> {
+    let directives = serde_wasm_bindgen::from_value::<Option<String>>(directives)?
+        .map(|directives| LogDirectives::parse(&directives))
+        .transpose()
+        .map_err(|e| JsError::new(e.as_str()))?;
+    let sink = sink.map(|callback| {
+        Rc::new(LogSink {
+            callback,
+            directives,
+        })
+    });
+    LOG_SINK.with(|log_sink| *log_sink.borrow_mut() = sink);
+
+    Ok(())
 }
 
 #[wasm_bindgen]
@@ -113,6 +220,26 @@ pub async fn get_runtime_version(code: JsValue) -> Result<JsValue, JsError> {
 pub async fn calculate_state_root(
     entries: JsValue,
     trie_version: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let entries = serde_wasm_bindgen::from_value::<Vec<(HexString, HexString)>>(entries)?;
+    let trie_version = serde_wasm_bindgen::from_value::<u8>(trie_version)?;
+    let trie_version = TrieEntryVersion::try_from(trie_version)
+        .map_err(|_| JsError::new("invalid trie version"))?;
+    let hash_function = self::hash_function(hash_function)?;
+    let hash = task::calculate_state_root(entries, trie_version, hash_function);
+    let result = serde_wasm_bindgen::to_value(&hash)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn trie_nodes(
+    entries: JsValue,
+    trie_version: JsValue,
+    hash_function: JsValue,
 ) -> Result<JsValue, JsError> {
     setup_console(None);
 
@@ -120,27 +247,83 @@ pub async fn calculate_state_root(
     let trie_version = serde_wasm_bindgen::from_value::<u8>(trie_version)?;
     let trie_version = TrieEntryVersion::try_from(trie_version)
         .map_err(|_| JsError::new("invalid trie version"))?;
-    let hash = task::calculate_state_root(entries, trie_version);
+    let hash_function = self::hash_function(hash_function)?;
+    let nodes = state_trie::trie_nodes(entries, trie_version, hash_function);
+    let result = serde_wasm_bindgen::to_value(&nodes)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn create_state_trie(
+    entries: JsValue,
+    trie_version: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let entries = serde_wasm_bindgen::from_value::<Vec<(HexString, HexString)>>(entries)?;
+    let trie_version = serde_wasm_bindgen::from_value::<u8>(trie_version)?;
+    let trie_version = TrieEntryVersion::try_from(trie_version)
+        .map_err(|_| JsError::new("invalid trie version"))?;
+    let hash_function = self::hash_function(hash_function)?;
+    let id = state_trie::create_state_trie(entries, trie_version, hash_function);
+    let result = serde_wasm_bindgen::to_value(&id)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn update_state_trie(id: JsValue, diff: JsValue) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let id = serde_wasm_bindgen::from_value::<u32>(id)?;
+    let diff = serde_wasm_bindgen::from_value::<Vec<(HexString, Option<HexString>)>>(diff)?;
+    let hash = state_trie::update_state_trie(id, diff).map_err(|e| JsError::new(e.as_str()))?;
     let result = serde_wasm_bindgen::to_value(&hash)?;
 
     Ok(result)
 }
 
 #[wasm_bindgen]
-pub async fn decode_proof(trie_root_hash: JsValue, nodes: JsValue) -> Result<JsValue, JsError> {
+pub async fn delete_state_trie(id: JsValue) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let id = serde_wasm_bindgen::from_value::<u32>(id)?;
+    let deleted = state_trie::delete_state_trie(id);
+    let result = serde_wasm_bindgen::to_value(&deleted)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn decode_proof(
+    trie_root_hash: JsValue,
+    nodes: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
     setup_console(None);
 
     let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
     let nodes = serde_wasm_bindgen::from_value::<Vec<HexString>>(nodes)?;
-    let entries = proof::decode_proof(trie_root_hash, nodes.into_iter().map(|x| x.0).collect())
-        .map_err(|e| JsError::new(e.as_str()))?;
+    let hash_function = self::hash_function(hash_function)?;
+    let entries = proof::decode_proof(
+        trie_root_hash,
+        nodes.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
     let result = serde_wasm_bindgen::to_value(&entries)?;
 
     Ok(result)
 }
 
 #[wasm_bindgen]
-pub async fn create_proof(nodes: JsValue, updates: JsValue) -> Result<JsValue, JsError> {
+pub async fn create_proof(
+    nodes: JsValue,
+    updates: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
     setup_console(None);
 
     let proof = serde_wasm_bindgen::from_value::<Vec<HexString>>(nodes)?;
@@ -150,13 +333,159 @@ pub async fn create_proof(nodes: JsValue, updates: JsValue) -> Result<JsValue, J
             .into_iter()
             .map(|(key, value)| (key.0, value.map(|x| x.0))),
     );
-    let proof = proof::create_proof(proof.into_iter().map(|x| x.0).collect(), updates)
-        .map_err(|e| JsError::new(e.as_str()))?;
+    let hash_function = self::hash_function(hash_function)?;
+    let proof = proof::create_proof(
+        proof.into_iter().map(|x| x.0).collect(),
+        updates,
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
     let result = serde_wasm_bindgen::to_value(&proof)?;
 
     Ok(result)
 }
 
+#[wasm_bindgen]
+pub async fn encode_compact_proof(
+    trie_root_hash: JsValue,
+    nodes: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let nodes = serde_wasm_bindgen::from_value::<Vec<HexString>>(nodes)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let compact = proof::encode_compact_proof(
+        trie_root_hash,
+        nodes.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&compact)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn compact_proof_to_nodes(
+    trie_root_hash: JsValue,
+    compact: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let compact = serde_wasm_bindgen::from_value::<Vec<HexString>>(compact)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let nodes = proof::compact_proof_to_nodes(
+        trie_root_hash,
+        compact.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&nodes)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn decode_compact_proof(
+    trie_root_hash: JsValue,
+    compact: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let compact = serde_wasm_bindgen::from_value::<Vec<HexString>>(compact)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let entries = proof::decode_compact_proof(
+        trie_root_hash,
+        compact.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&entries)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn merge_proofs(
+    trie_root_hash: JsValue,
+    proofs: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let proofs = serde_wasm_bindgen::from_value::<Vec<Vec<HexString>>>(proofs)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let proof = proof::merge_proofs(
+        trie_root_hash,
+        proofs
+            .into_iter()
+            .map(|nodes| nodes.into_iter().map(|x| x.0).collect())
+            .collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&proof)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn prune_proof(
+    trie_root_hash: JsValue,
+    nodes: JsValue,
+    keys: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let nodes = serde_wasm_bindgen::from_value::<Vec<HexString>>(nodes)?;
+    let keys = serde_wasm_bindgen::from_value::<Vec<HexString>>(keys)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let proof = proof::prune_proof(
+        trie_root_hash,
+        nodes.into_iter().map(|x| x.0).collect(),
+        keys.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&proof)?;
+
+    Ok(result)
+}
+
+#[wasm_bindgen]
+pub async fn proof_stats(
+    trie_root_hash: JsValue,
+    nodes: JsValue,
+    keys: JsValue,
+    hash_function: JsValue,
+) -> Result<JsValue, JsError> {
+    setup_console(None);
+
+    let trie_root_hash = serde_wasm_bindgen::from_value::<HashHexString>(trie_root_hash)?;
+    let nodes = serde_wasm_bindgen::from_value::<Vec<HexString>>(nodes)?;
+    let keys = serde_wasm_bindgen::from_value::<Option<Vec<HexString>>>(keys)?;
+    let hash_function = self::hash_function(hash_function)?;
+    let stats = proof::proof_stats(
+        trie_root_hash,
+        nodes.into_iter().map(|x| x.0).collect(),
+        keys.map(|keys| keys.into_iter().map(|x| x.0).collect()),
+        hash_function,
+    )
+    .map_err(|e| JsError::new(e.as_str()))?;
+    let result = serde_wasm_bindgen::to_value(&stats)?;
+
+    Ok(result)
+}
+
 #[wasm_bindgen]
 pub async fn run_task(task: JsValue, js: JsCallback) -> Result<JsValue, JsValue> {
     let task = serde_wasm_bindgen::from_value::<task::TaskCall>(task)?;
@@ -168,6 +497,27 @@ pub async fn run_task(task: JsValue, js: JsCallback) -> Result<JsValue, JsValue>
     Ok(result)
 }
 
+#[wasm_bindgen]
+pub async fn run_offchain_worker(call: JsValue, js: JsCallback) -> Result<JsValue, JsValue> {
+    let call = serde_wasm_bindgen::from_value::<task::OffchainWorkerCall>(call)?;
+    let task = task::TaskCall::from(call);
+    setup_console(task.log_level());
+
+    let result = task::run_task(task, js).await?;
+    let result = serde_wasm_bindgen::to_value(&result)?;
+
+    Ok(result)
+}
+
+/// Aborts the running task `task_id` the next time it calls the host, see `TaskCall::timeout`.
+#[wasm_bindgen]
+pub async fn cancel_task(task_id: JsValue) -> Result<(), JsError> {
+    let task_id = serde_wasm_bindgen::from_value::<u32>(task_id)?;
+    cancel::cancel(task_id);
+
+    Ok(())
+}
+
 #[wasm_bindgen]
 pub async fn testing(js: JsCallback, key: JsValue) -> Result<JsValue, JsValue> {
     setup_console(None);
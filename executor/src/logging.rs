@@ -0,0 +1,83 @@
+use log::{Level, LevelFilter};
+
+/// Log levels per target, parsed from `RUST_LOG`-style directives such as
+/// `info,runtime::evm=trace`. The longest matching target prefix wins, logs of other targets
+/// use the default level.
+#[derive(Debug, Clone)]
+pub struct LogDirectives {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogDirectives {
+    pub fn new(default: LevelFilter) -> Self {
+        LogDirectives {
+            default,
+            targets: vec![],
+        }
+    }
+
+    pub fn parse(directives: &str) -> Result<Self, String> {
+        let mut parsed = LogDirectives::new(LevelFilter::Off);
+
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let invalid = || format!("Invalid log directive `{directive}`");
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = level.trim().parse().map_err(|_| invalid())?;
+                    parsed.targets.push((target.trim().to_string(), level));
+                }
+                None => match directive.parse() {
+                    Ok(level) => parsed.default = level,
+                    // a bare target enables all of its logs
+                    Err(_) => parsed
+                        .targets
+                        .push((directive.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Highest level enabled for any target.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let max_level = self
+            .targets
+            .iter()
+            .filter(|(name, _)| target.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |(_, level)| *level);
+        level <= max_level
+    }
+}
+
+#[test]
+fn log_directives_works() {
+    let directives = LogDirectives::parse("warn, runtime::evm=trace,runtime=info,xcm").unwrap();
+    assert_eq!(directives.max_level(), LevelFilter::Trace);
+
+    assert!(directives.enabled("runtime::evm", Level::Trace));
+    assert!(directives.enabled("runtime::evm::call", Level::Debug));
+    assert!(directives.enabled("runtime::system", Level::Info));
+    assert!(!directives.enabled("runtime::system", Level::Debug));
+    assert!(directives.enabled("xcm::barriers", Level::Trace));
+    assert!(directives.enabled("aura", Level::Warn));
+    assert!(!directives.enabled("aura", Level::Info));
+
+    let directives = LogDirectives::parse("runtime=debug").unwrap();
+    assert_eq!(directives.max_level(), LevelFilter::Debug);
+    assert!(!directives.enabled("aura", Level::Error));
+
+    assert!(LogDirectives::parse("runtime=loud").is_err());
+}
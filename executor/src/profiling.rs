@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use smoldot::executor::runtime_call::{OffchainContext, RuntimeCall};
+use std::{collections::BTreeMap, future::Future};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    pub fn performance_now() -> f64;
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HostFunctionProfile {
+    count: u32,
+    /// Milliseconds, including the time waiting on `JsCallback`.
+    time: f64,
+}
+
+/// Where the time of a runtime call went. Only the host functions that smoldot hands over to the
+/// executor are listed, the others (storage writes, hashing, allocator, ...) count as wasm time.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CallProfile {
+    call: String,
+    /// Milliseconds spent in the runtime and the host functions smoldot handles itself.
+    wasm_time: f64,
+    /// Milliseconds spent waiting on `JsCallback`.
+    callback_time: f64,
+    host_functions: Vec<(String, HostFunctionProfile)>,
+    /// Size of the storage values read.
+    bytes_read: usize,
+    /// Size of the storage values written by the call.
+    bytes_written: usize,
+}
+
+/// Collects the profile of a runtime call. Does nothing if disabled.
+pub struct Profiler {
+    enabled: bool,
+    last: f64,
+    host_function: Option<&'static str>,
+    host_functions: BTreeMap<&'static str, HostFunctionProfile>,
+    profile: CallProfile,
+}
+
+impl Profiler {
+    pub fn new(enabled: bool, call: &str) -> Self {
+        Profiler {
+            enabled,
+            last: if enabled { performance_now() } else { 0.0 },
+            host_function: None,
+            host_functions: BTreeMap::new(),
+            profile: CallProfile {
+                call: call.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Must be called when the runtime yields `vm`.
+    pub fn enter(&mut self, vm: &RuntimeCall) {
+        if !self.enabled {
+            return;
+        }
+        let now = performance_now();
+        self.profile.wasm_time += now - self.last;
+        self.last = now;
+        self.host_function = host_function_name(vm);
+    }
+
+    /// Must be called before resuming the runtime.
+    pub fn leave(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let now = performance_now();
+        if let Some(name) = self.host_function.take() {
+            let profile = self.host_functions.entry(name).or_default();
+            profile.count += 1;
+            profile.time += now - self.last;
+        }
+        self.last = now;
+    }
+
+    pub async fn callback<F: Future>(&mut self, callback: F) -> F::Output {
+        if !self.enabled {
+            return callback.await;
+        }
+        let start = performance_now();
+        let output = callback.await;
+        self.profile.callback_time += performance_now() - start;
+        output
+    }
+
+    pub fn read(&mut self, bytes: usize) {
+        self.profile.bytes_read += bytes;
+    }
+
+    pub fn write(&mut self, bytes: usize) {
+        self.profile.bytes_written += bytes;
+    }
+
+    pub fn finish(mut self) -> Option<CallProfile> {
+        self.profile.host_functions = self
+            .host_functions
+            .into_iter()
+            .map(|(name, profile)| (name.to_string(), profile))
+            .collect();
+        self.enabled.then_some(self.profile)
+    }
+}
+
+fn host_function_name(vm: &RuntimeCall) -> Option<&'static str> {
+    Some(match vm {
+        RuntimeCall::Finished(_) => return None,
+        RuntimeCall::StorageGet(_) => "storage_get",
+        RuntimeCall::ClosestDescendantMerkleValue(_) => "closest_descendant_merkle_value",
+        RuntimeCall::NextKey(_) => "next_key",
+        RuntimeCall::SignatureVerification(_) => "signature_verification",
+        RuntimeCall::LogEmit(_) => "log",
+        RuntimeCall::OffchainStorageSet(_) => "offchain_index_set",
+        RuntimeCall::Offchain(OffchainContext::StorageGet(_)) => "offchain_storage_get",
+        RuntimeCall::Offchain(OffchainContext::StorageSet(_)) => "offchain_storage_set",
+        RuntimeCall::Offchain(OffchainContext::Timestamp(_)) => "offchain_timestamp",
+        RuntimeCall::Offchain(OffchainContext::RandomSeed(_)) => "offchain_random_seed",
+        RuntimeCall::Offchain(OffchainContext::SubmitTransaction(_)) => {
+            "offchain_submit_transaction"
+        }
+    })
+}
@@ -1,17 +1,48 @@
+use crate::{
+    state_trie::{StateTrie, CHILD_TRIE_KEY_LEN},
+    task::DEFAULT_CHILD_STORAGE_PREFIX,
+};
+use serde::{Deserialize, Serialize};
+use sha3::Digest;
 use smoldot::{
     json_rpc::methods::{HashHexString, HexString},
     trie::{
         bytes_to_nibbles, nibbles_to_bytes_suffix_extend,
         proof_decode::{decode_and_verify_proof, Config, StorageValue},
-        proof_encode::ProofBuilder,
-        trie_node, trie_structure, Nibble,
+        trie_node, HashFunction, Nibble, TrieEntryVersion,
     },
 };
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Prefixed to a compact node whose hashed storage value follows it as a separate item.
+const COMPACT_ESCAPE_HEADER: u8 = 0x01;
 
 pub fn decode_proof(
     trie_root_hash: HashHexString,
     nodes: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<Vec<(HexString, HexString)>, String> {
+    // smoldot only verifies Blake2 proofs
+    if matches!(hash_function, HashFunction::Blake2) {
+        return decode_blake2_proof(trie_root_hash, nodes);
+    }
+
+    let nodes = verify_proof_nodes(nodes, hash_function)?;
+
+    let mut entries = vec![];
+    if let Some(root) = nodes.get(&trie_root_hash.0) {
+        decode_proof_node(&nodes, root, true, &mut entries)?;
+    }
+
+    Ok(entries
+        .into_iter()
+        .map(|(key, value)| (HexString(key), HexString(value)))
+        .collect())
+}
+
+fn decode_blake2_proof(
+    trie_root_hash: HashHexString,
+    nodes: Vec<Vec<u8>>,
 ) -> Result<Vec<(HexString, HexString)>, String> {
     let config = Config::<Vec<u8>> {
         proof: encode_proofs(nodes),
@@ -41,117 +72,192 @@ pub fn decode_proof(
     Ok(entries)
 }
 
+/// Indexes the proof nodes by hash. Like smoldot's proof decoding, rejects duplicated entries and
+/// entries that are neither a trie node nor a storage value referenced by one.
+fn verify_proof_nodes(
+    nodes: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<HashMap<[u8; 32], Vec<u8>>, String> {
+    let count = nodes.len();
+    let nodes = index_nodes(nodes, hash_function);
+    if nodes.len() != count {
+        return Err("Proof contains duplicate entries".to_string());
+    }
+
+    let mut values = HashSet::new();
+    for node in nodes.values() {
+        if let Ok(decoded) = trie_node::decode(node) {
+            if let trie_node::StorageValue::Hashed(value_hash) = decoded.storage_value {
+                values.insert(*value_hash);
+            }
+        }
+    }
+    if nodes
+        .iter()
+        .any(|(hash, node)| !values.contains(hash) && trie_node::decode(node).is_err())
+    {
+        return Err("Proof contains an entry that is not used".to_string());
+    }
+
+    Ok(nodes)
+}
+
+/// Collects the entries below `root` in key order. Children missing from the proof are skipped,
+/// as are hashed values unless `hashed_values` is set.
+///
+/// Walks the trie with an explicit stack, the depth of an untrusted proof is unbounded.
+fn decode_proof_node(
+    nodes: &HashMap<[u8; 32], Vec<u8>>,
+    root: &[u8],
+    hashed_values: bool,
+    entries: &mut Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<(), String> {
+    // nodes left to visit, with the length of their parent key and their nibble in the parent
+    let mut stack = vec![(root, 0, None)];
+    let mut key = Vec::new();
+
+    while let Some((node, parent_key_len, nibble)) = stack.pop() {
+        let decoded = trie_node::decode(node).map_err(|e| e.to_string())?;
+        key.truncate(parent_key_len);
+        key.extend(nibble);
+        key.extend(decoded.partial_key);
+
+        let value = match decoded.storage_value {
+            trie_node::StorageValue::Unhashed(value) => Some(value),
+            trie_node::StorageValue::Hashed(value_hash) if hashed_values => {
+                nodes.get(value_hash).map(|x| &x[..])
+            }
+            trie_node::StorageValue::Hashed(_) | trie_node::StorageValue::None => None,
+        };
+        if let Some(value) = value {
+            if !key.len().is_multiple_of(2) {
+                return Err("Storage value at a key with an odd number of nibbles".to_string());
+            }
+            entries.push((
+                nibbles_to_bytes_suffix_extend(key.iter().copied()).collect(),
+                value.to_vec(),
+            ));
+        }
+
+        // pushed in reverse so that children are visited in key order
+        for (nibble, child) in decoded.children.iter().enumerate().rev() {
+            let Some(child) = child else {
+                continue;
+            };
+            let child = match <&[u8; 32]>::try_from(*child) {
+                Ok(child_hash) => match nodes.get(child_hash) {
+                    Some(child) => &child[..],
+                    None => continue,
+                },
+                // inline node
+                Err(_) => child,
+            };
+            let nibble = Nibble::try_from(u8::try_from(nibble).unwrap()).unwrap();
+            stack.push((child, key.len(), Some(nibble)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies `updates` on top of the entries of the proof and returns the root hash and nodes of
+/// the resulting V0 trie. Hashed values of the proof are not kept.
+///
+/// Entries of a child trie whose root is stored under `:child_storage:default:<child>` in the
+/// proof are keyed `:child_storage:default:<child><key>`, and go back to a child trie like in
+/// `StateTrie`.
 pub fn create_proof(
     nodes: Vec<Vec<u8>>,
     updates: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    hash_function: HashFunction,
 ) -> Result<(HashHexString, Vec<HexString>), String> {
-    let config = Config::<Vec<u8>> {
-        proof: encode_proofs(nodes),
+    let nodes = if matches!(hash_function, HashFunction::Blake2) {
+        // smoldot only verifies Blake2 proofs
+        decode_and_verify_proof(Config::<Vec<u8>> {
+            proof: encode_proofs(nodes.clone()),
+        })
+        .map_err(|e| e.to_string())?;
+        index_nodes(nodes, hash_function)
+    } else {
+        verify_proof_nodes(nodes, hash_function)?
     };
-    let decoded = decode_and_verify_proof(config).map_err(|e| e.to_string())?;
-    let mut proof_builder = ProofBuilder::new();
-
-    let mut trie = trie_structure::TrieStructure::new();
-
-    let mut deletes: Vec<Vec<u8>> = vec![];
 
+    let mut entries = proof_entries(&nodes)?;
     for (key, value) in updates {
-        if let Some(value) = value {
-            trie.node(bytes_to_nibbles(key.iter().cloned()))
-                .into_vacant()
-                .unwrap()
-                .insert_storage_value()
-                .insert(value.to_vec(), vec![]);
-        } else {
-            deletes.push(key);
-        }
+        match value {
+            Some(value) => entries.insert(key, value),
+            None => entries.remove(&key),
+        };
     }
 
-    for (entry_key, value) in decoded.iter_ordered() {
-        let decoded_value = trie_node::decode(value.node_value).unwrap();
+    let (trie_root_hash, nodes) = StateTrie::new(
+        entries.into_iter().collect(),
+        TrieEntryVersion::V0,
+        hash_function,
+    )
+    .nodes();
 
-        if let trie_structure::Entry::Vacant(vacant) = trie.node(entry_key.key) {
-            if let trie_node::StorageValue::Unhashed(value) = decoded_value.storage_value {
-                vacant.insert_storage_value().insert(value.to_vec(), vec![]);
+    Ok((
+        HashHexString(trie_root_hash),
+        nodes.into_values().map(HexString).collect(),
+    ))
+}
+
+/// Collects the entries of every trie of the proof, without the hashed values. Entries of child
+/// tries are keyed `:child_storage:default:<child><key>`.
+fn proof_entries(nodes: &HashMap<[u8; 32], Vec<u8>>) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, String> {
+    // every node not referenced by another one is the root of a trie in the proof
+    let mut referenced = HashSet::new();
+    for node in nodes.values() {
+        if let Ok(decoded) = trie_node::decode(node) {
+            if let trie_node::StorageValue::Hashed(value_hash) = decoded.storage_value {
+                referenced.insert(*value_hash);
+            }
+            for child in decoded.children.into_iter().flatten() {
+                if let Ok(child_hash) = <[u8; 32]>::try_from(child) {
+                    referenced.insert(child_hash);
+                }
             }
         }
     }
 
-    for key in deletes {
-        if let trie_structure::Entry::Occupied(occupied) =
-            trie.node(bytes_to_nibbles(key.iter().cloned()))
-        {
-            if occupied.has_storage_value() {
-                occupied.into_storage().unwrap().remove();
-            }
+    let mut tries = HashMap::new();
+    for (hash, node) in nodes {
+        if referenced.contains(hash) {
+            continue;
         }
+        let mut entries = vec![];
+        decode_proof_node(nodes, node, false, &mut entries)?;
+        tries.insert(*hash, entries);
     }
 
-    for node_index in trie.clone().iter_unordered() {
-        let key = trie
-            .node_full_key_by_index(node_index)
-            .unwrap()
-            .collect::<Vec<_>>();
-
-        let has_storage_value = trie.node_by_index(node_index).unwrap().has_storage_value();
-        let storage_value = if has_storage_value {
-            trie.node_by_index(node_index)
-                .unwrap()
-                .into_storage()
-                .unwrap()
-                .user_data()
-                .clone()
-        } else {
-            vec![]
-        };
-
-        let decoded = trie_node::Decoded {
-            children: std::array::from_fn(|nibble| {
-                let nibble = Nibble::try_from(u8::try_from(nibble).unwrap()).unwrap();
-                if trie
-                    .node_by_index(node_index)
-                    .unwrap()
-                    .child_user_data(nibble)
-                    .is_some()
-                {
-                    Some(&[][..])
-                } else {
-                    None
+    // roots of the child tries, stored in another trie of the proof
+    let mut child_keys = HashMap::new();
+    for entries in tries.values() {
+        for (key, value) in entries {
+            let Some(child) = key.strip_prefix(DEFAULT_CHILD_STORAGE_PREFIX) else {
+                continue;
+            };
+            if let Ok(child_root) = <[u8; 32]>::try_from(&value[..]) {
+                if child.len() == CHILD_TRIE_KEY_LEN && tries.contains_key(&child_root) {
+                    child_keys.insert(child_root, child);
                 }
-            }),
-            partial_key: trie
-                .node_by_index(node_index)
-                .unwrap()
-                .partial_key()
-                .collect::<Vec<_>>()
-                .into_iter(),
-            storage_value: if has_storage_value {
-                trie_node::StorageValue::Unhashed(&storage_value[..])
-            } else {
-                trie_node::StorageValue::None
-            },
-        };
-
-        let node_value = trie_node::encode_to_vec(decoded)
-            .map_err(|e| format!("Failed to encode node proof {:?}", e.to_string()))?;
-
-        proof_builder.set_node_value(&key, &node_value, None)
+            }
+        }
     }
 
-    assert!(proof_builder.missing_node_values().next().is_none());
-    proof_builder.make_coherent();
-    let trie_root_hash = proof_builder.trie_root_hash().unwrap();
-
-    let nodes = proof_builder
-        .build()
-        .map(|x| HexString(x.as_ref().to_vec()))
-        .skip(1) // length of nodes
-        .enumerate()
-        .filter(|(i, _)| i % 2 != 0) // length of each nodes
-        .map(|(_, v)| v) // node itself
-        .collect::<Vec<_>>();
+    let mut flat_entries = BTreeMap::new();
+    for (root, entries) in &tries {
+        let prefix = match child_keys.get(root) {
+            Some(child) => [DEFAULT_CHILD_STORAGE_PREFIX, child].concat(),
+            None => vec![],
+        };
+        for (key, value) in entries {
+            flat_entries.insert([&prefix[..], key].concat(), value.clone());
+        }
+    }
 
-    Ok((HashHexString(trie_root_hash), nodes))
+    Ok(flat_entries)
 }
 
 fn encode_proofs(nodes: Vec<Vec<u8>>) -> Vec<u8> {
@@ -191,6 +297,496 @@ fn encode_scale_compact_usize(mut value: usize) -> impl AsRef<[u8]> + Clone {
     array
 }
 
+/// Converts a list of proof nodes into the compact format used by `CompactProof` and parachain
+/// PoVs. Child references and hashed values that are part of the proof are omitted, and the
+/// nodes are ordered depth-first starting from `trie_root_hash`, followed by child tries.
+pub fn encode_compact_proof(
+    trie_root_hash: HashHexString,
+    nodes: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<Vec<HexString>, String> {
+    let nodes = index_nodes(nodes, hash_function);
+
+    let mut compact = vec![];
+    let mut child_roots = vec![];
+    encode_compact_node(
+        &nodes,
+        &trie_root_hash.0,
+        Some(&mut child_roots),
+        &mut compact,
+    )?;
+    for child_root in child_roots {
+        encode_compact_node(&nodes, &child_root, None, &mut compact)?;
+    }
+
+    Ok(compact.into_iter().map(HexString).collect())
+}
+
+/// Converts a compact proof back into the list of proof nodes, checking that it matches
+/// `trie_root_hash`.
+pub fn compact_proof_to_nodes(
+    trie_root_hash: HashHexString,
+    compact: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<Vec<HexString>, String> {
+    let mut items = compact.iter().map(|x| &x[..]).peekable();
+    let mut nodes = vec![];
+
+    let root = decode_compact_node(&mut items, hash_function, &mut nodes)?;
+    if root != trie_root_hash.0 {
+        return Err("Compact proof does not match trie root hash".to_string());
+    }
+    // remaining items are child tries
+    while items.peek().is_some() {
+        decode_compact_node(&mut items, hash_function, &mut nodes)?;
+    }
+
+    // identical subtries are encoded once per occurrence
+    let mut seen = HashSet::new();
+    nodes.retain(|node| seen.insert(trie_hash(hash_function, node)));
+
+    Ok(nodes.into_iter().map(HexString).collect())
+}
+
+pub fn decode_compact_proof(
+    trie_root_hash: HashHexString,
+    compact: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<Vec<(HexString, HexString)>, String> {
+    let nodes = compact_proof_to_nodes(trie_root_hash.clone(), compact, hash_function)?;
+    decode_proof(
+        trie_root_hash,
+        nodes.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofStats {
+    node_count: usize,
+    total_bytes: usize,
+    keys: Vec<KeyProofCost>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyProofCost {
+    key: HexString,
+    node_count: usize,
+    bytes: usize,
+    /// Bytes of the nodes that no other key of the proof needs.
+    exclusive_bytes: usize,
+}
+
+/// Merges proofs generated against the same trie root into a single proof without duplicated
+/// nodes.
+pub fn merge_proofs(
+    trie_root_hash: HashHexString,
+    proofs: Vec<Vec<Vec<u8>>>,
+    hash_function: HashFunction,
+) -> Result<Vec<HexString>, String> {
+    let mut seen = HashSet::new();
+    let nodes = proofs
+        .into_iter()
+        .flatten()
+        .filter(|node| seen.insert(trie_hash(hash_function, node)))
+        .collect::<Vec<_>>();
+
+    let reachable = reachable_nodes(
+        &index_nodes(nodes.clone(), hash_function),
+        &trie_root_hash.0,
+    );
+    if nodes
+        .iter()
+        .any(|node| !reachable.contains(&trie_hash(hash_function, node)))
+    {
+        return Err("Proof node is not connected to the trie root".to_string());
+    }
+
+    Ok(nodes.into_iter().map(HexString).collect())
+}
+
+/// Removes the nodes of the proof that are not needed to prove the given keys.
+pub fn prune_proof(
+    trie_root_hash: HashHexString,
+    nodes: Vec<Vec<u8>>,
+    keys: Vec<Vec<u8>>,
+    hash_function: HashFunction,
+) -> Result<Vec<HexString>, String> {
+    let nodes = index_nodes(nodes, hash_function);
+
+    let mut needed = BTreeMap::new();
+    for key in keys {
+        for hash in key_path(&nodes, &trie_root_hash.0, &key)? {
+            needed.entry(hash).or_insert_with(|| nodes[&hash].clone());
+        }
+    }
+
+    Ok(needed.into_values().map(HexString).collect())
+}
+
+/// Reports the size of the proof and how much of it each key needs. Defaults to every key with a
+/// value in the proof if `keys` is not given.
+pub fn proof_stats(
+    trie_root_hash: HashHexString,
+    nodes: Vec<Vec<u8>>,
+    keys: Option<Vec<Vec<u8>>>,
+    hash_function: HashFunction,
+) -> Result<ProofStats, String> {
+    let keys = match keys {
+        Some(keys) => keys,
+        None => decode_proof(trie_root_hash.clone(), nodes.clone(), hash_function)?
+            .into_iter()
+            .map(|(key, _)| key.0)
+            .collect(),
+    };
+    let nodes = index_nodes(nodes, hash_function);
+
+    let paths = keys
+        .into_iter()
+        .map(|key| {
+            let path = key_path(&nodes, &trie_root_hash.0, &key)?;
+            Ok((key, path))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut usage = HashMap::<[u8; 32], usize>::new();
+    for (_, path) in &paths {
+        for hash in path {
+            *usage.entry(*hash).or_default() += 1;
+        }
+    }
+
+    let keys = paths
+        .into_iter()
+        .map(|(key, path)| KeyProofCost {
+            key: HexString(key),
+            node_count: path.len(),
+            bytes: path.iter().map(|hash| nodes[hash].len()).sum(),
+            exclusive_bytes: path
+                .iter()
+                .filter(|hash| usage[*hash] == 1)
+                .map(|hash| nodes[hash].len())
+                .sum(),
+        })
+        .collect();
+
+    Ok(ProofStats {
+        node_count: nodes.len(),
+        total_bytes: nodes.values().map(|node| node.len()).sum(),
+        keys,
+    })
+}
+
+/// Returns the hashes of the proof nodes visited when looking up `key`, including the node of a
+/// hashed storage value. A key that is absent from the trie yields the nodes proving its
+/// absence.
+fn key_path(
+    nodes: &HashMap<[u8; 32], Vec<u8>>,
+    trie_root_hash: &[u8; 32],
+    key: &[u8],
+) -> Result<Vec<[u8; 32]>, String> {
+    let incomplete = || format!("Proof is incomplete for key {:?}", HexString(key.to_vec()));
+
+    let key = bytes_to_nibbles(key.iter().copied()).collect::<Vec<_>>();
+    let mut key = &key[..];
+
+    let mut path = vec![*trie_root_hash];
+    let mut node = &nodes.get(trie_root_hash).ok_or_else(incomplete)?[..];
+
+    loop {
+        let decoded = trie_node::decode(node).map_err(|e| e.to_string())?;
+        let partial_key = decoded.partial_key.collect::<Vec<_>>();
+        if !key.starts_with(&partial_key) {
+            break;
+        }
+        key = &key[partial_key.len()..];
+
+        let Some((nibble, rest)) = key.split_first() else {
+            if let trie_node::StorageValue::Hashed(value_hash) = decoded.storage_value {
+                if !nodes.contains_key(value_hash) {
+                    return Err(incomplete());
+                }
+                path.push(*value_hash);
+            }
+            break;
+        };
+        let Some(child) = decoded.children[usize::from(*nibble)] else {
+            break;
+        };
+        key = rest;
+
+        node = match <[u8; 32]>::try_from(child) {
+            Ok(child_hash) => {
+                path.push(child_hash);
+                &nodes.get(&child_hash).ok_or_else(incomplete)?[..]
+            }
+            // inline node
+            Err(_) => child,
+        };
+    }
+
+    Ok(path)
+}
+
+/// Returns the hashes of the proof nodes reachable from `trie_root_hash`, following child trie
+/// roots stored in the main trie.
+fn reachable_nodes(
+    nodes: &HashMap<[u8; 32], Vec<u8>>,
+    trie_root_hash: &[u8; 32],
+) -> HashSet<[u8; 32]> {
+    let mut reachable = HashSet::new();
+    let mut stack = vec![*trie_root_hash];
+
+    while let Some(hash) = stack.pop() {
+        let Some(node) = nodes.get(&hash) else {
+            continue;
+        };
+        if !reachable.insert(hash) {
+            continue;
+        }
+        // value nodes are not decodable
+        let Ok(decoded) = trie_node::decode(node) else {
+            continue;
+        };
+
+        let mut inline_nodes = vec![decoded];
+        while let Some(decoded) = inline_nodes.pop() {
+            match decoded.storage_value {
+                trie_node::StorageValue::Hashed(value_hash) => stack.push(*value_hash),
+                trie_node::StorageValue::Unhashed(value) => {
+                    if let Ok(child_root) = <[u8; 32]>::try_from(value) {
+                        stack.push(child_root);
+                    }
+                }
+                trie_node::StorageValue::None => {}
+            }
+            for child in decoded.children.into_iter().flatten() {
+                match <[u8; 32]>::try_from(child) {
+                    Ok(child_hash) => stack.push(child_hash),
+                    Err(_) => {
+                        if let Ok(decoded) = trie_node::decode(child) {
+                            inline_nodes.push(decoded);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    reachable
+}
+
+/// Appends the compact encoding of the trie below `trie_root_hash` to `compact`, depth first. If
+/// `child_roots` is set, the roots of the child tries referenced from this trie and present in
+/// `nodes` are collected into it.
+///
+/// Walks the trie with an explicit stack, the depth of an untrusted proof is unbounded.
+fn encode_compact_node(
+    nodes: &HashMap<[u8; 32], Vec<u8>>,
+    trie_root_hash: &[u8; 32],
+    mut child_roots: Option<&mut Vec<[u8; 32]>>,
+    compact: &mut Vec<Vec<u8>>,
+) -> Result<(), String> {
+    // nodes left to encode, with the length of their parent key and their nibble in the parent
+    let mut stack = vec![(*trie_root_hash, 0, None)];
+    let mut key = Vec::new();
+
+    while let Some((hash, parent_key_len, nibble)) = stack.pop() {
+        let node = nodes
+            .get(&hash)
+            .ok_or_else(|| "Missing trie node in proof".to_string())?;
+        let decoded = trie_node::decode(node).map_err(|e| e.to_string())?;
+
+        // children that are part of the proof are rebuilt when decoding
+        let omitted: [bool; 16] = std::array::from_fn(|nibble| {
+            decoded.children[nibble]
+                .and_then(|child| <&[u8; 32]>::try_from(child).ok())
+                .is_some_and(|child| nodes.contains_key(child))
+        });
+        let attached_value = match decoded.storage_value {
+            trie_node::StorageValue::Hashed(value_hash) => nodes.get(value_hash),
+            _ => None,
+        };
+
+        let mut encoded = trie_node::encode_to_vec(trie_node::Decoded {
+            children: std::array::from_fn(|nibble| {
+                if omitted[nibble] {
+                    Some(&[][..])
+                } else {
+                    decoded.children[nibble]
+                }
+            }),
+            partial_key: decoded.partial_key.clone(),
+            storage_value: if attached_value.is_some() {
+                trie_node::StorageValue::Unhashed(&[])
+            } else {
+                decoded.storage_value
+            },
+        })
+        .map_err(|e| e.to_string())?;
+
+        if let Some(value) = attached_value {
+            encoded.insert(0, COMPACT_ESCAPE_HEADER);
+            compact.push(encoded);
+            compact.push(value.clone());
+        } else {
+            compact.push(encoded);
+        }
+
+        key.truncate(parent_key_len);
+        key.extend(nibble);
+        key.extend(decoded.partial_key.clone());
+
+        // collect child trie roots referenced from the main trie
+        if let (Some(child_roots), trie_node::StorageValue::Unhashed(value)) =
+            (child_roots.as_deref_mut(), decoded.storage_value)
+        {
+            if key.len().is_multiple_of(2) {
+                let key = nibbles_to_bytes_suffix_extend(key.iter().copied()).collect::<Vec<_>>();
+                if let Ok(child_root) = <[u8; 32]>::try_from(value) {
+                    if key.starts_with(DEFAULT_CHILD_STORAGE_PREFIX)
+                        && nodes.contains_key(&child_root)
+                    {
+                        child_roots.push(child_root);
+                    }
+                }
+            }
+        }
+
+        // pushed in reverse so that children are encoded in key order
+        for (nibble, child) in decoded.children.iter().enumerate().rev() {
+            if !omitted[nibble] {
+                continue;
+            }
+            let child = <[u8; 32]>::try_from(child.unwrap()).unwrap();
+            let nibble = Nibble::try_from(u8::try_from(nibble).unwrap()).unwrap();
+            stack.push((child, key.len(), Some(nibble)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Node of a compact proof whose omitted children are being decoded.
+struct CompactNode<'a> {
+    /// Slot of the node in the decoded nodes, reserved so that nodes keep the depth-first order.
+    index: usize,
+    /// Nibble under which the parent references the node.
+    nibble: usize,
+    partial_key: Vec<Nibble>,
+    storage_value: trie_node::StorageValue<'a>,
+    value_hash: Option<[u8; 32]>,
+    children: [Option<Vec<u8>>; 16],
+    /// Omitted children left to decode, the next one last.
+    omitted: Vec<usize>,
+}
+
+/// Decodes the next trie of a compact proof into `nodes` and returns its root hash.
+///
+/// Walks the trie with an explicit stack, the depth of an untrusted proof is unbounded.
+fn decode_compact_node<'a>(
+    items: &mut impl Iterator<Item = &'a [u8]>,
+    hash_function: HashFunction,
+    nodes: &mut Vec<Vec<u8>>,
+) -> Result<[u8; 32], String> {
+    let mut stack = vec![read_compact_node(items, hash_function, nodes, 0)?];
+
+    loop {
+        let node = stack.last_mut().unwrap();
+        if let Some(nibble) = node.omitted.pop() {
+            let child = read_compact_node(items, hash_function, nodes, nibble)?;
+            stack.push(child);
+            continue;
+        }
+
+        let node = stack.pop().unwrap();
+        let encoded = trie_node::encode_to_vec(trie_node::Decoded {
+            children: std::array::from_fn(|nibble| node.children[nibble].as_deref()),
+            partial_key: node.partial_key.into_iter(),
+            storage_value: match &node.value_hash {
+                Some(value_hash) => trie_node::StorageValue::Hashed(value_hash),
+                None => node.storage_value,
+            },
+        })
+        .map_err(|e| e.to_string())?;
+
+        let hash = trie_hash(hash_function, &encoded);
+        nodes[node.index] = encoded;
+        match stack.last_mut() {
+            Some(parent) => parent.children[node.nibble] = Some(hash.to_vec()),
+            None => return Ok(hash),
+        }
+    }
+}
+
+fn read_compact_node<'a>(
+    items: &mut impl Iterator<Item = &'a [u8]>,
+    hash_function: HashFunction,
+    nodes: &mut Vec<Vec<u8>>,
+    nibble: usize,
+) -> Result<CompactNode<'a>, String> {
+    let item = items
+        .next()
+        .ok_or_else(|| "Compact proof is incomplete".to_string())?;
+    let (escaped, item) = match item.split_first() {
+        Some((&COMPACT_ESCAPE_HEADER, rest)) => (true, rest),
+        _ => (false, item),
+    };
+    let decoded = trie_node::decode(item).map_err(|e| e.to_string())?;
+
+    let index = nodes.len();
+    nodes.push(vec![]);
+
+    let value_hash = if escaped {
+        let value = items
+            .next()
+            .ok_or_else(|| "Compact proof is missing attached value".to_string())?;
+        nodes.push(value.to_vec());
+        Some(trie_hash(hash_function, value))
+    } else {
+        None
+    };
+
+    Ok(CompactNode {
+        index,
+        nibble,
+        partial_key: decoded.partial_key.collect(),
+        storage_value: decoded.storage_value,
+        value_hash,
+        children: std::array::from_fn(|nibble| match decoded.children[nibble] {
+            Some([]) | None => None,
+            Some(child) => Some(child.to_vec()),
+        }),
+        omitted: (0..16)
+            .rev()
+            .filter(|nibble| decoded.children[*nibble] == Some(&[][..]))
+            .collect(),
+    })
+}
+
+fn index_nodes(nodes: Vec<Vec<u8>>, hash_function: HashFunction) -> HashMap<[u8; 32], Vec<u8>> {
+    nodes
+        .into_iter()
+        .map(|node| (trie_hash(hash_function, &node), node))
+        .collect()
+}
+
+pub fn trie_hash(hash_function: HashFunction, data: &[u8]) -> [u8; 32] {
+    match hash_function {
+        HashFunction::Blake2 => blake2_256(data),
+        HashFunction::Keccak256 => sha3::Keccak256::digest(data).into(),
+    }
+}
+
+pub fn blake2_256(data: &[u8]) -> [u8; 32] {
+    blake2_rfc::blake2b::blake2b(32, &[], data)
+        .as_bytes()
+        .try_into()
+        .unwrap()
+}
+
 #[test]
 fn create_proof_works() {
     use hex_literal::hex;
@@ -214,11 +810,12 @@ fn create_proof_works() {
         (upgrade_go_ahead_signal.clone().0, Some(hex!("01").to_vec())),
     ]);
 
-    let (hash, nodes) = create_proof(get_nodes(), updates).unwrap();
+    let (hash, nodes) = create_proof(get_nodes(), updates, HashFunction::Blake2).unwrap();
 
     let decoded = decode_proof(
         hash.clone(),
         nodes.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+        HashFunction::Blake2,
     )
     .unwrap();
 
@@ -248,9 +845,13 @@ fn create_proof_works() {
 
     // delete entries
     let updates = BTreeMap::<Vec<u8>, Option<Vec<u8>>>::from([(dmq_mqc_head.clone().0, None)]);
-    let (hash, nodes) = create_proof(get_nodes(), updates).unwrap();
-    let decoded =
-        decode_proof(hash, nodes.iter().map(|x| x.0.clone()).collect::<Vec<_>>()).unwrap();
+    let (hash, nodes) = create_proof(get_nodes(), updates, HashFunction::Blake2).unwrap();
+    let decoded = decode_proof(
+        hash,
+        nodes.iter().map(|x| x.0.clone()).collect::<Vec<_>>(),
+        HashFunction::Blake2,
+    )
+    .unwrap();
     assert!(decoded
         .iter()
         .find(|(key, _)| key == &dmq_mqc_head)
@@ -273,10 +874,248 @@ fn decode_proof_works() {
     let root = HashHexString(hex!(
         "4a8902b29241020b24b4a1620d0154f756b81ffbcf739a9f06d3447df8123ebd"
     ));
-    let result = decode_proof(root, get_nodes()).unwrap();
+    let result = decode_proof(root, get_nodes(), HashFunction::Blake2).unwrap();
     println!("{:#?}", result);
 }
 
+#[test]
+fn compact_proof_works() {
+    use hex_literal::hex;
+
+    let root = HashHexString(hex!(
+        "4a8902b29241020b24b4a1620d0154f756b81ffbcf739a9f06d3447df8123ebd"
+    ));
+    let compact = encode_compact_proof(root.clone(), get_nodes(), HashFunction::Blake2).unwrap();
+    assert!(
+        compact.iter().map(|x| x.0.len()).sum::<usize>()
+            < get_nodes().iter().map(|x| x.len()).sum::<usize>()
+    );
+
+    let compact = compact.into_iter().map(|x| x.0).collect::<Vec<_>>();
+    let mut nodes = compact_proof_to_nodes(root.clone(), compact.clone(), HashFunction::Blake2)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.0)
+        .collect::<Vec<_>>();
+    nodes.sort();
+    let mut expected = get_nodes();
+    expected.sort();
+    assert_eq!(nodes, expected);
+
+    assert_eq!(
+        decode_compact_proof(root.clone(), compact.clone(), HashFunction::Blake2).unwrap(),
+        decode_proof(root, get_nodes(), HashFunction::Blake2).unwrap()
+    );
+
+    // wrong root
+    let root = HashHexString([0u8; 32]);
+    assert!(compact_proof_to_nodes(root, compact, HashFunction::Blake2).is_err());
+}
+
+#[test]
+fn compact_proof_matches_substrate() {
+    use hex_literal::hex;
+
+    // V1 trie with a hashed value and the default child trie `child`, compact proof generated by
+    // sp-trie `StorageProof::into_compact_proof`
+    let root = HashHexString(hex!(
+        "83b418c3e8d625b2b80e917f962050aa46b5ed7b683ee24829068ceb71507a5b"
+    ));
+    let mut nodes = vec![
+        hex!("0707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707").to_vec(),
+        hex!("146f6240002fb96cd406b641cdb3eb0728bbc6d118b43f2cb6416f1ff1a478ebb8a62591c5144302790478").to_vec(),
+        hex!("203065c83390099b24bdc4881179191d8fa80aacda4f75e27dd870638c4996eeeb").to_vec(),
+        hex!("42424242424242424242424242424242424242424242424242424242424242424242424242424242").to_vec(),
+        hex!("770a6368696c645f73746f726167653a64656661756c743a6368696c6480b5eb7bb80570fea0a7d40d725128465bb6ab4528932127cf7610201bbaf98306").to_vec(),
+        hex!("8006002c486c6963651473686f7274803af49d03cd18bad4cb92afee94c96f71efc206e15000e241b1a5f633e7d01bf8").to_vec(),
+        hex!("804800808f8484f0dc690fd292cef5d0885fe5bd5a7523928c572b301bf98fb4cbbca027809db5db028bd821b97f91e1bab27af9aa1b93524db814782424816d1423da0ab3").to_vec(),
+        hex!("8306b30600104008763180519ce31c93c80007b36f434703677f97c801580820d43b73d7a1cdd08cd65f6f").to_vec(),
+    ];
+    let compact = vec![
+        hex!("8048000000").to_vec(),
+        hex!("770a6368696c645f73746f726167653a64656661756c743a6368696c6480b5eb7bb80570fea0a7d40d725128465bb6ab4528932127cf7610201bbaf98306").to_vec(),
+        hex!("8006002c486c6963651473686f727400").to_vec(),
+        // escaped branch, its value follows
+        hex!("01c46f62400000144302790478").to_vec(),
+        hex!("42424242424242424242424242424242424242424242424242424242424242424242424242424242").to_vec(),
+        // child trie
+        hex!("8306b30600104008763100").to_vec(),
+        hex!("014000").to_vec(),
+        hex!("0707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707070707").to_vec(),
+    ];
+
+    let encoded = encode_compact_proof(root.clone(), nodes.clone(), HashFunction::Blake2).unwrap();
+    assert_eq!(
+        encoded.into_iter().map(|x| x.0).collect::<Vec<_>>(),
+        compact
+    );
+
+    let mut decoded = compact_proof_to_nodes(root.clone(), compact.clone(), HashFunction::Blake2)
+        .unwrap()
+        .into_iter()
+        .map(|x| x.0)
+        .collect::<Vec<_>>();
+    decoded.sort();
+    nodes.sort();
+    assert_eq!(decoded, nodes);
+
+    let entries = decode_compact_proof(root, compact, HashFunction::Blake2).unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            (
+                b":child_storage:default:child".to_vec(),
+                hex!("b5eb7bb80570fea0a7d40d725128465bb6ab4528932127cf7610201bbaf98306").to_vec()
+            ),
+            (b"alice".to_vec(), b"short".to_vec()),
+            (b"bob".to_vec(), vec![0x42; 40]),
+            (b"bobby".to_vec(), b"x".to_vec()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (HexString(key), HexString(value)))
+        .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn merge_and_prune_proofs_works() {
+    use hex_literal::hex;
+
+    let root = HashHexString(hex!(
+        "4a8902b29241020b24b4a1620d0154f756b81ffbcf739a9f06d3447df8123ebd"
+    ));
+    let current_slot = hex!("1cb6f36e027abb2091cfb5110ab5087f06155b3cd9a8c9e5e9a23fd5dc13a5ed");
+    let active_config = hex!("06de3d8a54d27e44a9d5ce189618f22db4b49d95320d9021994c850f25b8e385");
+
+    let slot_proof = prune_proof(
+        root.clone(),
+        get_nodes(),
+        vec![current_slot.to_vec()],
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    let config_proof = prune_proof(
+        root.clone(),
+        get_nodes(),
+        vec![active_config.to_vec()],
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    assert!(slot_proof.len() < get_nodes().len());
+
+    let decoded = decode_proof(
+        root.clone(),
+        slot_proof.iter().map(|x| x.0.clone()).collect(),
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    assert!(decoded.iter().any(|(key, _)| key.0 == current_slot));
+    assert!(!decoded.iter().any(|(key, _)| key.0 == active_config));
+
+    let merged = merge_proofs(
+        root.clone(),
+        vec![
+            slot_proof.iter().map(|x| x.0.clone()).collect(),
+            config_proof.iter().map(|x| x.0.clone()).collect(),
+        ],
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    // the root node is shared
+    assert!(merged.len() < slot_proof.len() + config_proof.len());
+    let decoded = decode_proof(
+        root.clone(),
+        merged.iter().map(|x| x.0.clone()).collect(),
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    assert!(decoded.iter().any(|(key, _)| key.0 == current_slot));
+    assert!(decoded.iter().any(|(key, _)| key.0 == active_config));
+
+    // nodes from another trie are rejected
+    let (_, other) = create_proof(
+        get_nodes(),
+        BTreeMap::from([(current_slot.to_vec(), Some(vec![0]))]),
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    assert!(merge_proofs(
+        root.clone(),
+        vec![get_nodes(), other.into_iter().map(|x| x.0).collect()],
+        HashFunction::Blake2,
+    )
+    .is_err());
+
+    let stats = proof_stats(
+        root,
+        merged.into_iter().map(|x| x.0).collect(),
+        Some(vec![current_slot.to_vec(), active_config.to_vec()]),
+        HashFunction::Blake2,
+    )
+    .unwrap();
+    assert_eq!(stats.keys.len(), 2);
+    assert_eq!(
+        stats.total_bytes,
+        stats.keys.iter().map(|x| x.exclusive_bytes).sum::<usize>() + stats.keys[0].bytes
+            - stats.keys[0].exclusive_bytes
+    );
+}
+
+#[test]
+fn keccak_proof_works() {
+    use crate::state_trie::StateTrie;
+
+    let entries = (0u8..50)
+        .map(|i| (vec![i % 3, i], vec![i; usize::from(i)]))
+        .collect::<Vec<_>>();
+    let (root, nodes) = StateTrie::new(
+        entries.clone(),
+        TrieEntryVersion::V1,
+        HashFunction::Keccak256,
+    )
+    .nodes();
+    let root = HashHexString(root);
+    let nodes = nodes.into_values().collect::<Vec<_>>();
+
+    let mut decoded = decode_proof(root.clone(), nodes.clone(), HashFunction::Keccak256)
+        .unwrap()
+        .into_iter()
+        .map(|(key, value)| (key.0, value.0))
+        .collect::<Vec<_>>();
+    decoded.sort();
+    let mut expected = entries.clone();
+    expected.sort();
+    assert_eq!(decoded, expected);
+
+    let compact =
+        encode_compact_proof(root.clone(), nodes.clone(), HashFunction::Keccak256).unwrap();
+    let compact = compact.into_iter().map(|x| x.0).collect::<Vec<_>>();
+    assert_eq!(
+        decode_compact_proof(root.clone(), compact, HashFunction::Keccak256).unwrap(),
+        decode_proof(root.clone(), nodes.clone(), HashFunction::Keccak256).unwrap()
+    );
+
+    // duplicated and unused entries are rejected
+    let duplicated = [nodes.clone(), vec![nodes[0].clone()]].concat();
+    assert!(decode_proof(root.clone(), duplicated, HashFunction::Keccak256).is_err());
+    let unused = [nodes.clone(), vec![vec![0xff; 40]]].concat();
+    assert!(decode_proof(root.clone(), unused.clone(), HashFunction::Keccak256).is_err());
+    assert!(create_proof(unused, BTreeMap::new(), HashFunction::Keccak256).is_err());
+
+    // hashed values are not kept
+    let (hash, _) = create_proof(nodes, BTreeMap::new(), HashFunction::Keccak256).unwrap();
+    let (root, _) = StateTrie::new(
+        entries
+            .into_iter()
+            .filter(|(_, value)| value.len() < 33)
+            .collect(),
+        TrieEntryVersion::V0,
+        HashFunction::Keccak256,
+    )
+    .nodes();
+    assert_eq!(hash.0, root);
+}
+
 #[cfg(test)]
 fn get_nodes() -> Vec<Vec<u8>> {
     use hex_literal::hex;
@@ -340,3 +1179,48 @@ fn get_nodes() -> Vec<Vec<u8>> {
 		hex!("9f0d7fefc408aac59dbfe80a72ac8e3ce5cebf80b9cbd0d700a17a0e4c717af58b887e80c0bce7832e64817caa8b47152baf5ba0807e1ee2c8362cd0745aa0c58780b99fefa1024c1b472d3386a2319e71b68028a68012d23785dbec11744e5f0fef2c34efcb738891240cd4ad63bb101bd4c2512a0980152c557d36cdee4289c8b2c42462576e238b62422b257e91773a473f49c3858680a8842b069c158af198d7d570838f69a2f4c9a218d51c69ad60361a873a11bbea80f08851397f0db3ac9785de1017f41cb5b073b92ecf6b4653d1f38d4993028e4680117820f0023f9e54736ad10ecae8806bcf1cc8b4ed694aa530ed85e6361db7ea80be32eae63e4a223cdf5cbf90b66f48665668ec0bedbb6b76302768ea6f3b19cc8030b143a144cc26437db830e7d750f21dbc87cf3140097b1fdbddb697782a237180ec76035be1c7d52a15dcf76aaf69fced1772f1464ec7886952dada14030ad4fc809b912d6aa76e61c4b515535db14d28490924ef2007cf19e0022b5a089b6d010380f28a57be8a23f6a2588595e53cb640bbeeee2ef9ddc962a6672ac98bd2d6ea4e").to_vec(),
     ].into_iter().collect::<Vec<_>>()
 }
+
+#[test]
+fn deep_proofs_dont_overflow() {
+    const DEPTH: usize = 20_000;
+    let hash_function = HashFunction::Keccak256;
+
+    let branch = |child: &[u8]| {
+        trie_node::encode_to_vec(trie_node::Decoded {
+            children: std::array::from_fn(|nibble| (nibble == 0).then_some(child)),
+            partial_key: std::iter::empty(),
+            storage_value: trie_node::StorageValue::None,
+        })
+        .unwrap()
+    };
+    let leaf = trie_node::encode_to_vec(trie_node::Decoded {
+        children: [None::<&[u8]>; 16],
+        partial_key: std::iter::empty(),
+        storage_value: trie_node::StorageValue::Unhashed(&[1]),
+    })
+    .unwrap();
+
+    // chain of branches, each one referencing the next by hash
+    let mut nodes = vec![leaf.clone()];
+    for _ in 0..DEPTH {
+        let child = trie_hash(hash_function, nodes.last().unwrap());
+        nodes.push(branch(&child));
+    }
+    let root = HashHexString(trie_hash(hash_function, nodes.last().unwrap()));
+
+    let entries = decode_proof(root.clone(), nodes.clone(), hash_function).unwrap();
+    assert_eq!(
+        entries,
+        vec![(HexString(vec![0; DEPTH / 2]), HexString(vec![1]))]
+    );
+
+    let compact = encode_compact_proof(root.clone(), nodes, hash_function).unwrap();
+    assert_eq!(compact.len(), DEPTH + 1);
+    let nodes = compact_proof_to_nodes(
+        root,
+        compact.into_iter().map(|x| x.0).collect(),
+        hash_function,
+    )
+    .unwrap();
+    assert_eq!(nodes.len(), DEPTH + 1);
+}
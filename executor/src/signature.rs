@@ -0,0 +1,352 @@
+use crate::proof::blake2_256;
+use serde::{Deserialize, Serialize};
+use smoldot::json_rpc::methods::HexString;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SignatureScheme {
+    Sr25519,
+    Ed25519,
+    Ecdsa,
+}
+
+impl SignatureScheme {
+    /// Returns the schemes that `public_key` can belong to. The host doesn't say which algorithm
+    /// is being verified, and sr25519 and ed25519 public keys are both 32 bytes long.
+    pub fn candidates(public_key: &[u8]) -> &'static [SignatureScheme] {
+        match public_key.len() {
+            33 => &[SignatureScheme::Ecdsa],
+            _ => &[SignatureScheme::Sr25519, SignatureScheme::Ed25519],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MockMode {
+    /// Verify every signature.
+    #[default]
+    None,
+    /// Accept signatures matching the magic pattern and verify the others.
+    Magic,
+    /// Accept every signature.
+    Always,
+}
+
+/// How a signature compares to the magic pattern.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum MagicMatch {
+    NoMatch,
+    /// Starts with the prefix but isn't filled with the expected byte.
+    PrefixOnly,
+    Match,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SignatureOutcome {
+    Mocked,
+    VerifiedTrue,
+    VerifiedFalse,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureCheck {
+    /// `None` for a 32-byte public key whose signature was verified, since sr25519 and ed25519
+    /// keys can't be told apart.
+    scheme: Option<SignatureScheme>,
+    public_key: HexString,
+    /// Blake2-256 of the signed message.
+    message_hash: HexString,
+    magic: MagicMatch,
+    outcome: SignatureOutcome,
+}
+
+impl SignatureCheck {
+    pub fn is_valid(&self) -> bool {
+        !matches!(self.outcome, SignatureOutcome::VerifiedFalse)
+    }
+}
+
+/// Signature starting with `prefix` and then filled with `fill`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MagicSignature {
+    prefix: HexString,
+    fill: u8,
+}
+
+impl Default for MagicSignature {
+    fn default() -> Self {
+        MagicSignature {
+            prefix: HexString(vec![0xde, 0xad, 0xbe, 0xef]),
+            fill: 0xcd,
+        }
+    }
+}
+
+impl MagicSignature {
+    pub fn matches(&self, signature: &[u8]) -> bool {
+        self.compare(signature) == MagicMatch::Match
+    }
+
+    fn compare(&self, signature: &[u8]) -> MagicMatch {
+        if !signature.starts_with(&self.prefix.0) {
+            MagicMatch::NoMatch
+        } else if signature[self.prefix.0.len()..]
+            .iter()
+            .all(|&b| b == self.fill)
+        {
+            MagicMatch::Match
+        } else {
+            MagicMatch::PrefixOnly
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SchemePolicy {
+    mode: MockMode,
+    /// Public keys the mock applies to, every key if not set.
+    accounts: Option<Vec<HexString>>,
+    magic: MagicSignature,
+}
+
+impl SchemePolicy {
+    fn new(mode: MockMode) -> Self {
+        SchemePolicy {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    fn should_mock(&self, public_key: &[u8], signature: &[u8]) -> bool {
+        if let Some(accounts) = &self.accounts {
+            if !accounts.iter().any(|account| account.0 == public_key) {
+                return false;
+            }
+        }
+        match self.mode {
+            MockMode::None => false,
+            MockMode::Magic => self.magic.matches(signature),
+            MockMode::Always => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MockSignaturePolicy {
+    sr25519: SchemePolicy,
+    ed25519: SchemePolicy,
+    ecdsa: SchemePolicy,
+}
+
+impl MockSignaturePolicy {
+    pub fn scheme(&self, scheme: SignatureScheme) -> &SchemePolicy {
+        match scheme {
+            SignatureScheme::Sr25519 => &self.sr25519,
+            SignatureScheme::Ed25519 => &self.ed25519,
+            SignatureScheme::Ecdsa => &self.ecdsa,
+        }
+    }
+
+    /// Rejects sr25519 and ed25519 policies that would leak into each other. The host can't tell
+    /// the two schemes apart, so accepting every signature of every account of one scheme would
+    /// also accept every signature of the other one, real GRANDPA and BEEFY signatures included.
+    /// A magic signature can't leak, a real signature never matches the pattern.
+    fn check_ambiguity(&self) -> Result<(), String> {
+        for (name, policy, other) in [
+            ("sr25519", &self.sr25519, &self.ed25519),
+            ("ed25519", &self.ed25519, &self.sr25519),
+        ] {
+            let accepts_everything = |policy: &SchemePolicy| {
+                policy.mode == MockMode::Always && policy.accounts.is_none()
+            };
+            if accepts_everything(policy) && !accepts_everything(other) {
+                return Err(format!(
+                    "Ambiguous mock signature policy: sr25519 and ed25519 public keys can't be told apart, \
+                     the {name} policy accepts every signature of every account so it must list its accounts"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decides whether the signature is mocked, calling `verify` otherwise. A 32-byte public key
+    /// is mocked if either the sr25519 or the ed25519 policy allows it.
+    pub fn check(
+        &self,
+        public_key: &[u8],
+        message: &[u8],
+        signature: &[u8],
+        verify: impl FnOnce() -> bool,
+    ) -> SignatureCheck {
+        let candidates = SignatureScheme::candidates(public_key);
+        let mocked_by = candidates
+            .iter()
+            .find(|scheme| self.scheme(**scheme).should_mock(public_key, signature))
+            .copied();
+
+        let (scheme, outcome) = match mocked_by {
+            Some(scheme) => (Some(scheme), SignatureOutcome::Mocked),
+            None => {
+                let scheme = match candidates {
+                    [scheme] => Some(*scheme),
+                    _ => None,
+                };
+                if verify() {
+                    (scheme, SignatureOutcome::VerifiedTrue)
+                } else {
+                    (scheme, SignatureOutcome::VerifiedFalse)
+                }
+            }
+        };
+
+        SignatureCheck {
+            scheme,
+            public_key: HexString(public_key.to_vec()),
+            message_hash: HexString(blake2_256(message).to_vec()),
+            magic: candidates
+                .iter()
+                .map(|scheme| self.scheme(*scheme).magic.compare(signature))
+                .max()
+                .unwrap_or(MagicMatch::NoMatch),
+            outcome,
+        }
+    }
+}
+
+/// Either a single mode applied to every scheme, 0: no mock, 1: require magic signature,
+/// 2: always valid, or a policy per scheme.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MockSignatureHost {
+    Mode(u8),
+    Policy(MockSignaturePolicy),
+}
+
+impl TryFrom<MockSignatureHost> for MockSignaturePolicy {
+    type Error = String;
+
+    fn try_from(host: MockSignatureHost) -> Result<Self, String> {
+        let mode = match host {
+            MockSignatureHost::Policy(policy) => {
+                policy.check_ambiguity()?;
+                return Ok(policy);
+            }
+            MockSignatureHost::Mode(1) => MockMode::Magic,
+            MockSignatureHost::Mode(2) => MockMode::Always,
+            MockSignatureHost::Mode(_) => MockMode::None,
+        };
+        Ok(MockSignaturePolicy {
+            sr25519: SchemePolicy::new(mode),
+            ed25519: SchemePolicy::new(mode),
+            ecdsa: SchemePolicy::new(mode),
+        })
+    }
+}
+
+#[test]
+fn magic_signature_works() {
+    let is_magic_signature = |signature: &[u8]| MagicSignature::default().matches(signature);
+
+    assert!(is_magic_signature(&[0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd]));
+    assert!(is_magic_signature(&[
+        0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd, 0xcd, 0xcd
+    ]));
+    assert!(!is_magic_signature(&[
+        0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd, 0xcd, 0x00
+    ]));
+    assert!(!is_magic_signature(&[
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+    ]));
+}
+
+#[test]
+fn mock_signature_policy_works() {
+    let alice = [1u8; 32];
+    let bob = [2u8; 32];
+    let ecdsa_key = [3u8; 33];
+    let magic = [&[0xde, 0xad, 0xbe, 0xef][..], &[0xcd; 60]].concat();
+    let other_magic = [&[0xaa][..], &[0xbb; 64]].concat();
+    let invalid = [0u8; 64];
+    let mocked = |policy: &MockSignaturePolicy, public_key: &[u8], signature: &[u8]| {
+        policy.check(public_key, &[], signature, || false).outcome == SignatureOutcome::Mocked
+    };
+
+    // legacy modes
+    let policy = MockSignaturePolicy::try_from(MockSignatureHost::Mode(0)).unwrap();
+    assert!(!mocked(&policy, &alice, &magic));
+    let policy = MockSignaturePolicy::try_from(MockSignatureHost::Mode(1)).unwrap();
+    assert!(mocked(&policy, &alice, &magic));
+    assert!(!mocked(&policy, &alice, &invalid));
+    let policy = MockSignaturePolicy::try_from(MockSignatureHost::Mode(2)).unwrap();
+    assert!(mocked(&policy, &ecdsa_key, &invalid));
+
+    let host = serde_json::from_str::<MockSignatureHost>(
+        r#"{
+            "sr25519": { "mode": "always", "accounts": ["0x0101010101010101010101010101010101010101010101010101010101010101"] },
+            "ecdsa": { "mode": "magic", "magic": { "prefix": "0xaa", "fill": 187 } }
+        }"#,
+    )
+    .unwrap();
+    let policy = MockSignaturePolicy::try_from(host).unwrap();
+
+    // only the allowlisted account is mocked
+    assert!(mocked(&policy, &alice, &invalid));
+    assert!(!mocked(&policy, &bob, &invalid));
+    assert!(!mocked(&policy, &bob, &magic));
+
+    // custom magic pattern
+    assert!(mocked(&policy, &ecdsa_key, &other_magic));
+    assert!(!mocked(&policy, &ecdsa_key, &magic));
+
+    assert!(matches!(
+        serde_json::from_str::<MockSignatureHost>("2").unwrap(),
+        MockSignatureHost::Mode(2)
+    ));
+
+    let check = policy.check(&bob, b"message", &invalid, || true);
+    assert_eq!(check.scheme, None);
+    assert_eq!(check.magic, MagicMatch::NoMatch);
+    assert_eq!(check.outcome, SignatureOutcome::VerifiedTrue);
+    let check = policy.check(&ecdsa_key, b"message", &[0xaa, 0xbb, 0x00], || false);
+    assert_eq!(check.scheme, Some(SignatureScheme::Ecdsa));
+    assert_eq!(check.magic, MagicMatch::PrefixOnly);
+    assert!(!check.is_valid());
+    let check = policy.check(&alice, b"message", &invalid, || false);
+    assert_eq!(check.scheme, Some(SignatureScheme::Sr25519));
+    assert!(check.is_valid());
+}
+
+#[test]
+fn ambiguous_mock_signature_policy_is_rejected() {
+    let policy = |json: &str| {
+        MockSignaturePolicy::try_from(serde_json::from_str::<MockSignatureHost>(json).unwrap())
+    };
+
+    // would also mock every ed25519 signature, GRANDPA and BEEFY included
+    assert!(policy(r#"{ "sr25519": { "mode": "always" } }"#).is_err());
+    assert!(
+        policy(r#"{ "ed25519": { "mode": "always" }, "sr25519": { "mode": "magic" } }"#).is_err()
+    );
+
+    // a real ed25519 signature never matches the magic pattern
+    assert!(policy(r#"{ "sr25519": { "mode": "magic" } }"#).is_ok());
+    assert!(policy(
+        r#"{ "sr25519": { "mode": "magic" }, "ed25519": { "mode": "magic", "magic": { "prefix": "0xaa", "fill": 187 } } }"#
+    )
+    .is_ok());
+    assert!(
+        policy(r#"{ "sr25519": { "mode": "always" }, "ed25519": { "mode": "always" } }"#).is_ok()
+    );
+    assert!(policy(
+        r#"{ "sr25519": { "mode": "always", "accounts": ["0x0101010101010101010101010101010101010101010101010101010101010101"] } }"#
+    )
+    .is_ok());
+    assert!(policy(r#"{ "ecdsa": { "mode": "always" } }"#).is_ok());
+}
@@ -0,0 +1,610 @@
+use crate::{proof::trie_hash, task::DEFAULT_CHILD_STORAGE_PREFIX};
+use serde::{Deserialize, Serialize};
+use smoldot::{
+    json_rpc::methods::HexString,
+    trie::{
+        bytes_to_nibbles, trie_node,
+        trie_structure::{Entry, NodeAccess, NodeIndex, Remove, TrieStructure},
+        HashFunction, Nibble, TrieEntryVersion, EMPTY_BLAKE2_TRIE_MERKLE_VALUE,
+        EMPTY_KECCAK256_TRIE_MERKLE_VALUE,
+    },
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
+
+thread_local! {
+    static STATE_TRIES: RefCell<HashMap<u32, StateTrie>> = Default::default();
+    static NEXT_STATE_TRIE_ID: Cell<u32> = const { Cell::new(0) };
+}
+
+#[derive(Default)]
+struct TrieNode {
+    value: Option<Vec<u8>>,
+    merkle_value: Option<CachedMerkleValue>,
+}
+
+/// Merkle value of a node, valid as long as nothing below the node changed. The partial key
+/// length and root flag are kept because inserting or removing a parent changes them without
+/// touching the node itself.
+struct CachedMerkleValue {
+    partial_key_len: usize,
+    is_root: bool,
+    merkle_value: trie_node::MerkleValueOutput,
+}
+
+/// Length of the child trie keys, as assumed by chopsticks' storage layer.
+pub const CHILD_TRIE_KEY_LEN: usize = 32;
+
+/// Trie kept in memory across calls so that applying a diff only recomputes the Merkle values
+/// of the nodes on the modified paths.
+///
+/// Keys are flat like in `CallResponse::storage_diff`: entries of the default child trie `child`
+/// are keyed `:child_storage:default:<child><key>` and go to a separate trie, whose root is
+/// written at `:child_storage:default:<child>` in the main trie.
+pub struct StateTrie {
+    trie: TrieStructure<TrieNode>,
+    child_tries: BTreeMap<Vec<u8>, StateTrie>,
+    trie_version: TrieEntryVersion,
+    hash_function: HashFunction,
+}
+
+impl StateTrie {
+    pub fn new(
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        trie_version: TrieEntryVersion,
+        hash_function: HashFunction,
+    ) -> Self {
+        let mut state_trie = StateTrie {
+            trie: TrieStructure::with_capacity(entries.len()),
+            child_tries: BTreeMap::new(),
+            trie_version,
+            hash_function,
+        };
+        state_trie.apply_diff(entries.into_iter().map(|(k, v)| (k, Some(v))));
+        state_trie
+    }
+
+    pub fn apply_diff(&mut self, diff: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>) {
+        let mut main_diff = vec![];
+        let mut updated_children = BTreeSet::new();
+        for (key, value) in diff {
+            let Some((child, child_key)) = split_child_key(&key) else {
+                main_diff.push((key, value));
+                continue;
+            };
+            let (trie_version, hash_function) = (self.trie_version, self.hash_function);
+            self.child_tries
+                .entry(child.to_vec())
+                .or_insert_with(|| StateTrie::new(vec![], trie_version, hash_function))
+                .apply_main_diff([(child_key.to_vec(), value)]);
+            updated_children.insert(child.to_vec());
+        }
+
+        for child in updated_children {
+            let child_root = match self.child_tries.get_mut(&child) {
+                Some(child_trie) if child_trie.trie.is_empty() => None,
+                Some(child_trie) => Some(child_trie.root_hash().to_vec()),
+                None => None,
+            };
+            if child_root.is_none() {
+                self.child_tries.remove(&child);
+            }
+            main_diff.push(([DEFAULT_CHILD_STORAGE_PREFIX, &child].concat(), child_root));
+        }
+
+        self.apply_main_diff(main_diff);
+    }
+
+    fn apply_main_diff(&mut self, diff: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>) {
+        for (key, value) in diff {
+            match (self.trie.node(bytes_to_nibbles(key.into_iter())), value) {
+                (Entry::Occupied(NodeAccess::Storage(mut node)), Some(value)) => {
+                    node.user_data().value = Some(value);
+                    invalidate(NodeAccess::Storage(node));
+                }
+                (Entry::Occupied(NodeAccess::Branch(node)), Some(value)) => {
+                    let mut node = node.insert_storage_value();
+                    node.user_data().value = Some(value);
+                    invalidate(NodeAccess::Storage(node));
+                }
+                (Entry::Vacant(vacant), Some(value)) => {
+                    let node = vacant.insert_storage_value().insert(
+                        TrieNode {
+                            value: Some(value),
+                            merkle_value: None,
+                        },
+                        TrieNode::default(),
+                    );
+                    invalidate(NodeAccess::Storage(node));
+                }
+                (Entry::Occupied(NodeAccess::Storage(node)), None) => match node.remove() {
+                    Remove::StorageToBranch(mut node) => {
+                        node.user_data().value = None;
+                        invalidate(NodeAccess::Branch(node));
+                    }
+                    Remove::SingleRemoveChild { child, .. } => {
+                        if let Some(parent) = child.into_parent() {
+                            invalidate(parent);
+                        }
+                    }
+                    Remove::SingleRemoveNoChild { parent, .. } => invalidate(parent),
+                    Remove::BranchAlsoRemoved { sibling, .. } => {
+                        if let Some(parent) = sibling.into_parent() {
+                            invalidate(parent);
+                        }
+                    }
+                    Remove::TrieNowEmpty { .. } => {}
+                },
+                // removing a key that doesn't exist
+                (Entry::Occupied(NodeAccess::Branch(_)) | Entry::Vacant(_), None) => {}
+            }
+        }
+    }
+
+    pub fn root_hash(&mut self) -> [u8; 32] {
+        let Some(root_index) = self.trie.root_node().map(|node| node.node_index()) else {
+            return match self.hash_function {
+                HashFunction::Blake2 => EMPTY_BLAKE2_TRIE_MERKLE_VALUE,
+                HashFunction::Keccak256 => EMPTY_KECCAK256_TRIE_MERKLE_VALUE,
+            };
+        };
+        self.merkle_value(root_index)
+            .try_into()
+            .expect("root node is always hashed")
+    }
+
+    /// Returns the root hash along with every node that isn't inlined in its parent, and the
+    /// hashed storage values, keyed by hash. Nodes of the child tries are included.
+    pub fn nodes(&mut self) -> ([u8; 32], BTreeMap<[u8; 32], Vec<u8>>) {
+        let mut nodes = BTreeMap::new();
+
+        let node_indices = self.trie.iter_ordered().collect::<Vec<_>>();
+        for node_index in node_indices {
+            let (node_value, is_root) = self.with_decoded_node(node_index, |decoded, is_root| {
+                let node_value = trie_node::encode_to_vec(decoded)
+                    .expect("nodes of the trie structure are always valid");
+                (node_value, is_root)
+            });
+            if is_root || node_value.len() >= 32 {
+                nodes.insert(trie_hash(self.hash_function, &node_value), node_value);
+            }
+
+            if let Some(value) = &self.trie[node_index].value {
+                if self.is_hashed_value(value) {
+                    nodes.insert(trie_hash(self.hash_function, value), value.clone());
+                }
+            }
+        }
+
+        for child_trie in self.child_tries.values_mut() {
+            nodes.extend(child_trie.nodes().1);
+        }
+
+        (self.root_hash(), nodes)
+    }
+
+    fn merkle_value(&mut self, node_index: NodeIndex) -> trie_node::MerkleValueOutput {
+        let mut node = self.trie.node_by_index(node_index).unwrap();
+        let partial_key_len = node.partial_key().len();
+        let is_root = node.is_root_node();
+
+        if let Some(cached) = &node.user_data().merkle_value {
+            if cached.partial_key_len == partial_key_len && cached.is_root == is_root {
+                return cached.merkle_value.clone();
+            }
+        }
+
+        let hash_function = self.hash_function;
+        let merkle_value = self.with_decoded_node(node_index, |decoded, is_root| {
+            trie_node::calculate_merkle_value(decoded, hash_function, is_root)
+                .expect("nodes of the trie structure are always valid")
+        });
+
+        self.trie[node_index].merkle_value = Some(CachedMerkleValue {
+            partial_key_len,
+            is_root,
+            merkle_value: merkle_value.clone(),
+        });
+
+        merkle_value
+    }
+
+    /// Builds the decoded form of a node, computing the Merkle values of its children.
+    fn with_decoded_node<R>(
+        &mut self,
+        node_index: NodeIndex,
+        f: impl FnOnce(
+            trie_node::Decoded<'_, std::iter::Copied<std::slice::Iter<'_, Nibble>>, &[u8]>,
+            bool,
+        ) -> R,
+    ) -> R {
+        let mut node = self.trie.node_by_index(node_index).unwrap();
+        let partial_key = node.partial_key().collect::<Vec<_>>();
+        let is_root = node.is_root_node();
+
+        let children_indices: [Option<NodeIndex>; 16] = std::array::from_fn(|nibble| {
+            let nibble = Nibble::try_from(u8::try_from(nibble).unwrap()).unwrap();
+            node.child(nibble).map(|child| child.node_index())
+        });
+        let children = children_indices.map(|child| child.map(|child| self.merkle_value(child)));
+
+        let value = self.trie[node_index].value.as_deref();
+        let value_hash;
+        let storage_value = match value {
+            Some(value) if self.is_hashed_value(value) => {
+                value_hash = trie_hash(self.hash_function, value);
+                trie_node::StorageValue::Hashed(&value_hash)
+            }
+            Some(value) => trie_node::StorageValue::Unhashed(value),
+            None => trie_node::StorageValue::None,
+        };
+
+        f(
+            trie_node::Decoded {
+                children: std::array::from_fn(|nibble| {
+                    children[nibble].as_ref().map(AsRef::as_ref)
+                }),
+                partial_key: partial_key.iter().copied(),
+                storage_value,
+            },
+            is_root,
+        )
+    }
+
+    fn is_hashed_value(&self, value: &[u8]) -> bool {
+        matches!(self.trie_version, TrieEntryVersion::V1) && value.len() >= 33
+    }
+}
+
+/// Splits a flat child trie entry key into the child trie key and the key within the child trie.
+fn split_child_key(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let key = key.strip_prefix(DEFAULT_CHILD_STORAGE_PREFIX)?;
+    if key.len() <= CHILD_TRIE_KEY_LEN {
+        // the child trie root in the main trie
+        return None;
+    }
+    Some(key.split_at(CHILD_TRIE_KEY_LEN))
+}
+
+/// Clears the cached Merkle value of the node and all of its ancestors.
+fn invalidate(mut node: NodeAccess<TrieNode>) {
+    loop {
+        node.user_data().merkle_value = None;
+        node = match node.into_parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrieNodes {
+    root: HexString,
+    nodes: Vec<(HexString, HexString)>,
+}
+
+pub fn trie_nodes(
+    entries: Vec<(HexString, HexString)>,
+    trie_version: TrieEntryVersion,
+    hash_function: HashFunction,
+) -> TrieNodes {
+    let mut state_trie = StateTrie::new(
+        entries.into_iter().map(|(k, v)| (k.0, v.0)).collect(),
+        trie_version,
+        hash_function,
+    );
+    let (root, nodes) = state_trie.nodes();
+    TrieNodes {
+        root: HexString(root.to_vec()),
+        nodes: nodes
+            .into_iter()
+            .map(|(hash, node)| (HexString(hash.to_vec()), HexString(node)))
+            .collect(),
+    }
+}
+
+pub fn create_state_trie(
+    entries: Vec<(HexString, HexString)>,
+    trie_version: TrieEntryVersion,
+    hash_function: HashFunction,
+) -> u32 {
+    let state_trie = StateTrie::new(
+        entries.into_iter().map(|(k, v)| (k.0, v.0)).collect(),
+        trie_version,
+        hash_function,
+    );
+    let id = NEXT_STATE_TRIE_ID.with(|next_id| next_id.replace(next_id.get() + 1));
+    STATE_TRIES.with(|tries| tries.borrow_mut().insert(id, state_trie));
+    id
+}
+
+pub fn update_state_trie(
+    id: u32,
+    diff: Vec<(HexString, Option<HexString>)>,
+) -> Result<HexString, String> {
+    STATE_TRIES.with(|tries| {
+        let mut tries = tries.borrow_mut();
+        let state_trie = tries
+            .get_mut(&id)
+            .ok_or_else(|| format!("State trie {id} not found"))?;
+        state_trie.apply_diff(diff.into_iter().map(|(k, v)| (k.0, v.map(|v| v.0))));
+        Ok(HexString(state_trie.root_hash().to_vec()))
+    })
+}
+
+pub fn delete_state_trie(id: u32) -> bool {
+    STATE_TRIES.with(|tries| tries.borrow_mut().remove(&id).is_some())
+}
+
+#[test]
+fn state_trie_works() {
+    use crate::task::calculate_state_root;
+
+    for (trie_version, hash_function) in [
+        (TrieEntryVersion::V0, HashFunction::Blake2),
+        (TrieEntryVersion::V1, HashFunction::Blake2),
+        (TrieEntryVersion::V0, HashFunction::Keccak256),
+    ] {
+        let mut entries = BTreeMap::<Vec<u8>, Vec<u8>>::new();
+        for i in 0u8..200 {
+            entries.insert(vec![i % 7, i], vec![i; usize::from(i % 50)]);
+            entries.insert(vec![i % 7, i, 1, 2], vec![i]);
+        }
+        let root_of = |entries: &BTreeMap<Vec<u8>, Vec<u8>>| {
+            calculate_state_root(
+                entries
+                    .iter()
+                    .map(|(k, v)| (HexString(k.clone()), HexString(v.clone())))
+                    .collect(),
+                trie_version,
+                hash_function,
+            )
+            .0
+        };
+
+        let mut state_trie = StateTrie::new(
+            entries.clone().into_iter().collect(),
+            trie_version,
+            hash_function,
+        );
+        assert_eq!(state_trie.root_hash().to_vec(), root_of(&entries));
+
+        let mut diff = vec![];
+        for i in (0u8..200).step_by(3) {
+            // update
+            diff.push((vec![i % 7, i], Some(vec![i; 40])));
+            // remove leaf, collapsing branches
+            diff.push((vec![i % 7, i, 1, 2], None));
+            // insert
+            diff.push((vec![i % 7, i, 1], Some(vec![i, i])));
+        }
+        // remove missing key
+        diff.push((vec![0xff], None));
+
+        for (key, value) in &diff {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        state_trie.apply_diff(diff);
+        assert_eq!(state_trie.root_hash().to_vec(), root_of(&entries));
+
+        // empty trie
+        state_trie.apply_diff(
+            entries
+                .keys()
+                .map(|k| (k.clone(), None))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            state_trie.root_hash().to_vec(),
+            root_of(&Default::default())
+        );
+    }
+}
+
+#[test]
+fn keccak_state_root_matches_substrate() {
+    use crate::task::calculate_state_root;
+    use hex_literal::hex;
+
+    // roots computed by trie-db with sp-core's `KeccakHasher`
+    let entries = (0u8..100)
+        .map(|i| {
+            (
+                HexString(vec![i % 5, i]),
+                HexString(vec![i; usize::from(i)]),
+            )
+        })
+        .collect::<Vec<_>>();
+    for (trie_version, hash_function, root) in [
+        (
+            TrieEntryVersion::V0,
+            HashFunction::Keccak256,
+            hex!("cd71b5ba9317e5ac04159517ff9267ebe01e14afab6d965c018a32db7477c4a7"),
+        ),
+        (
+            TrieEntryVersion::V1,
+            HashFunction::Keccak256,
+            hex!("f2c432a87cbedf869040fe9ab53803d5a49748b1f9248006d26192982d969b41"),
+        ),
+        (
+            TrieEntryVersion::V1,
+            HashFunction::Blake2,
+            hex!("cf0edb78d56ff5ec9edb00c78a313e549c0190fc7f61de80388911093b402723"),
+        ),
+    ] {
+        assert_eq!(
+            calculate_state_root(entries.clone(), trie_version, hash_function).0,
+            root
+        );
+    }
+}
+
+#[test]
+fn trie_nodes_works() {
+    use crate::{proof::decode_proof, task::calculate_state_root};
+    use smoldot::json_rpc::methods::HashHexString;
+
+    for (trie_version, hash_function) in [
+        (TrieEntryVersion::V0, HashFunction::Blake2),
+        (TrieEntryVersion::V1, HashFunction::Blake2),
+        (TrieEntryVersion::V0, HashFunction::Keccak256),
+        (TrieEntryVersion::V1, HashFunction::Keccak256),
+    ] {
+        let mut entries = (0u8..100)
+            .map(|i| {
+                (
+                    HexString(vec![i % 5, i]),
+                    HexString(vec![i; usize::from(i)]),
+                )
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+
+        let TrieNodes { root, nodes } = trie_nodes(entries.clone(), trie_version, hash_function);
+        assert_eq!(
+            root,
+            calculate_state_root(entries.clone(), trie_version, hash_function)
+        );
+        for (hash, node) in &nodes {
+            assert_eq!(hash.0, trie_hash(hash_function, &node.0));
+        }
+
+        let decoded = decode_proof(
+            HashHexString(root.0.try_into().unwrap()),
+            nodes.into_iter().map(|(_, node)| node.0).collect(),
+            hash_function,
+        )
+        .unwrap();
+        assert_eq!(decoded, entries);
+    }
+}
+
+#[test]
+fn state_trie_child_trie_works() {
+    use crate::task::calculate_state_root;
+
+    let child = [7u8; 32];
+    let child_entry = |key: &[u8]| [DEFAULT_CHILD_STORAGE_PREFIX, &child, key].concat();
+    let root_of = |entries: Vec<(Vec<u8>, Vec<u8>)>| {
+        calculate_state_root(
+            entries
+                .into_iter()
+                .map(|(k, v)| (HexString(k), HexString(v)))
+                .collect(),
+            TrieEntryVersion::V1,
+            HashFunction::Blake2,
+        )
+        .0
+    };
+
+    let mut state_trie = StateTrie::new(
+        vec![
+            (b"main".to_vec(), vec![1]),
+            (child_entry(b"a"), vec![2]),
+            (child_entry(b"b"), vec![3; 40]),
+        ],
+        TrieEntryVersion::V1,
+        HashFunction::Blake2,
+    );
+    let child_root = root_of(vec![(b"a".to_vec(), vec![2]), (b"b".to_vec(), vec![3; 40])]);
+    assert_eq!(
+        state_trie.root_hash().to_vec(),
+        root_of(vec![
+            (b"main".to_vec(), vec![1]),
+            ([DEFAULT_CHILD_STORAGE_PREFIX, &child].concat(), child_root),
+        ])
+    );
+
+    state_trie.apply_diff(vec![
+        (child_entry(b"a"), None),
+        (child_entry(b"c"), Some(vec![4])),
+    ]);
+    let child_root = root_of(vec![(b"b".to_vec(), vec![3; 40]), (b"c".to_vec(), vec![4])]);
+    assert_eq!(
+        state_trie.root_hash().to_vec(),
+        root_of(vec![
+            (b"main".to_vec(), vec![1]),
+            ([DEFAULT_CHILD_STORAGE_PREFIX, &child].concat(), child_root),
+        ])
+    );
+
+    // killing the child trie removes its root
+    state_trie.apply_diff(vec![(child_entry(b"b"), None), (child_entry(b"c"), None)]);
+    assert_eq!(
+        state_trie.root_hash().to_vec(),
+        root_of(vec![(b"main".to_vec(), vec![1])])
+    );
+}
+
+#[test]
+fn child_trie_roots_agree() {
+    use crate::{proof::create_proof, task::calculate_state_root};
+
+    let child = [7u8; 32];
+    let child_entry = |key: &[u8]| [DEFAULT_CHILD_STORAGE_PREFIX, &child, key].concat();
+    let entries = vec![
+        (b"main".to_vec(), vec![1]),
+        (child_entry(b"a"), vec![2]),
+        (child_entry(b"b"), vec![3]),
+    ];
+    let hex_entries = |entries: &[(Vec<u8>, Vec<u8>)]| {
+        entries
+            .iter()
+            .map(|(k, v)| (HexString(k.clone()), HexString(v.clone())))
+            .collect::<Vec<_>>()
+    };
+
+    for (trie_version, hash_function) in [
+        (TrieEntryVersion::V0, HashFunction::Blake2),
+        (TrieEntryVersion::V1, HashFunction::Blake2),
+        (TrieEntryVersion::V0, HashFunction::Keccak256),
+        (TrieEntryVersion::V1, HashFunction::Keccak256),
+    ] {
+        let root = calculate_state_root(hex_entries(&entries), trie_version, hash_function);
+
+        // the child trie is separate, its root is stored in the main trie
+        let child_root = calculate_state_root(
+            hex_entries(&[(b"a".to_vec(), vec![2]), (b"b".to_vec(), vec![3])]),
+            trie_version,
+            hash_function,
+        );
+        let main_entries = [
+            (b"main".to_vec(), vec![1]),
+            (
+                [DEFAULT_CHILD_STORAGE_PREFIX, &child].concat(),
+                child_root.0,
+            ),
+        ];
+        assert_eq!(
+            root,
+            calculate_state_root(hex_entries(&main_entries), trie_version, hash_function)
+        );
+
+        let nodes = trie_nodes(hex_entries(&entries), trie_version, hash_function);
+        assert_eq!(nodes.root, root);
+        let nodes = nodes
+            .nodes
+            .into_iter()
+            .map(|(_, node)| node.0)
+            .collect::<Vec<_>>();
+
+        // values are short, so V0 and V1 tries are the same
+        let (proof_root, _) = create_proof(nodes.clone(), BTreeMap::new(), hash_function).unwrap();
+        assert_eq!(proof_root.0.to_vec(), root.0);
+
+        let updates = BTreeMap::from([(child_entry(b"c"), Some(vec![4]))]);
+        let (proof_root, _) = create_proof(nodes, updates, hash_function).unwrap();
+        let mut updated = entries.clone();
+        updated.push((child_entry(b"c"), vec![4]));
+        assert_eq!(
+            proof_root.0.to_vec(),
+            calculate_state_root(hex_entries(&updated), trie_version, hash_function).0
+        );
+    }
+}
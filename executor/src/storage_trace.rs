@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+use smoldot::json_rpc::methods::HexString;
+
+/// Storage access of a runtime call. Child trie keys are prefixed with
+/// `:child_storage:default:` and the child trie key.
+///
+/// Reads and next-key lookups are listed in the order the runtime made them. Writes are handled
+/// by smoldot and only known once the call finishes, so they follow the other accesses of their
+/// call, ordered by key.
+///
+/// The trace has gaps since smoldot keeps the writes of a call in its own overlay:
+/// - reads of keys written earlier in the same call are answered from the overlay and never reach
+///   the executor, so they are missing
+/// - `clear_prefix` and child trie kills aren't recorded as such, only as a write removing each
+///   key, sorted with the other writes at the end of the call
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum StorageAccess {
+    #[serde(rename_all = "camelCase")]
+    Read {
+        call_index: usize,
+        key: HexString,
+        value: Option<HexString>,
+    },
+    #[serde(rename_all = "camelCase")]
+    NextKey {
+        call_index: usize,
+        prefix: HexString,
+        key: HexString,
+        next_key: Option<HexString>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Write {
+        call_index: usize,
+        key: HexString,
+        old_value: Option<HexString>,
+        new_value: Option<HexString>,
+    },
+}
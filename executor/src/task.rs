@@ -1,19 +1,26 @@
-use core::{iter, ops::Bound};
+use crate::{
+    cancel::{TaskAbort, TaskAborted},
+    import_stubs::{stub_imports_cached, ImportStub},
+    logging::LogDirectives,
+    profiling::{CallProfile, Profiler},
+    proof::blake2_256,
+    signature::{MockSignatureHost, MockSignaturePolicy, SignatureCheck},
+    state_trie::StateTrie,
+    storage_trace::StorageAccess,
+};
+use core::iter;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::{from_value, to_value};
 use smoldot::{
     executor::{
-        host::{Config, HeapPages, HostVmPrototype, LogEmitInfo},
+        host::{self, Config, HeapPages, HostVmPrototype, LogEmitInfo},
         runtime_call::{self, OffchainContext, RuntimeCall},
         storage_diff::TrieDiff,
         CoreVersionRef,
     },
-    json_rpc::methods::HexString,
-    trie::{
-        bytes_to_nibbles,
-        calculate_root::{root_merkle_value, RootMerkleValueCalculation},
-        nibbles_to_bytes_suffix_extend, HashFunction, TrieEntryVersion,
-    },
+    json_rpc::methods::{HashHexString, HexString},
+    trie::{bytes_to_nibbles, nibbles_to_bytes_suffix_extend, HashFunction, TrieEntryVersion},
 };
 use std::collections::BTreeMap;
 use wasm_bindgen::prelude::*;
@@ -67,14 +74,105 @@ pub struct TaskCall {
     id: u32,
     wasm: HexString,
     calls: Vec<(String, Vec<HexString>)>,
-    mock_signature_host: u8, // 0: no mock, 1: require magic signature, 2: always valid
+    mock_signature_host: MockSignatureHost,
     allow_unresolved_imports: bool,
     runtime_log_level: u32,
+    /// `RUST_LOG`-style directives, e.g. `info,runtime::evm=trace`, overriding
+    /// `runtime_log_level`.
+    #[serde(default)]
+    runtime_log_directives: Option<String>,
     storage_proof_size: u64,
+    /// Timestamp served to offchain workers instead of calling `offchainTimestamp`.
+    #[serde(default)]
+    offchain_timestamp: Option<u64>,
+    /// Seed of the random values served to offchain workers instead of calling
+    /// `offchainRandomSeed`.
+    #[serde(default)]
+    offchain_random_seed: Option<HashHexString>,
+    /// Records where the time of each call goes.
+    #[serde(default)]
+    profile: bool,
+    /// Records every storage access of the calls.
+    #[serde(default)]
+    trace_storage: bool,
+    /// Milliseconds after which the task is aborted, see `TaskAbort`.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Built-in implementations of host functions smoldot lacks, by name, see `stub_imports`.
+    #[serde(default)]
+    import_stubs: BTreeMap<String, ImportStub>,
+    /// Sends each runtime log to `JsCallback::onLog` as soon as it is emitted, instead of the
+    /// executor log. Ignored if `onLog` isn't set.
+    #[serde(default)]
+    stream_logs: bool,
+}
+
+/// Runs `OffchainWorkerApi_offchain_worker` at the given block. Keystore and HTTP host functions
+/// are not available.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OffchainWorkerCall {
+    id: u32,
+    wasm: HexString,
+    /// SCALE-encoded header of the block.
+    header: HexString,
+    #[serde(default)]
+    runtime_log_level: u32,
+    #[serde(default)]
+    runtime_log_directives: Option<String>,
+    #[serde(default)]
+    offchain_timestamp: Option<u64>,
+    #[serde(default)]
+    offchain_random_seed: Option<HashHexString>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    stream_logs: bool,
+}
+
+impl From<OffchainWorkerCall> for TaskCall {
+    fn from(call: OffchainWorkerCall) -> Self {
+        TaskCall {
+            id: call.id,
+            wasm: call.wasm,
+            calls: vec![(
+                "OffchainWorkerApi_offchain_worker".to_string(),
+                vec![call.header],
+            )],
+            mock_signature_host: MockSignatureHost::Mode(0),
+            allow_unresolved_imports: false,
+            runtime_log_level: call.runtime_log_level,
+            runtime_log_directives: call.runtime_log_directives,
+            storage_proof_size: 0,
+            offchain_timestamp: call.offchain_timestamp,
+            offchain_random_seed: call.offchain_random_seed,
+            profile: false,
+            trace_storage: false,
+            timeout: call.timeout,
+            import_stubs: Default::default(),
+            stream_logs: call.stream_logs,
+        }
+    }
 }
 
 impl TaskCall {
+    /// Runtime log levels, `runtime_log_directives` if set, `runtime_log_level` for every target
+    /// otherwise.
+    pub fn log_directives(&self) -> Result<LogDirectives, String> {
+        match &self.runtime_log_directives {
+            Some(directives) => LogDirectives::parse(directives),
+            None => Ok(LogDirectives::new(
+                LevelFilter::iter()
+                    .nth(self.runtime_log_level as usize)
+                    .unwrap_or(LevelFilter::Trace),
+            )),
+        }
+    }
+
     pub fn log_level(&self) -> Option<log::Level> {
+        if let Ok(directives) = self.log_directives() {
+            return directives.max_level().to_level();
+        }
         match self.runtime_log_level {
             1 => Some(log::Level::Error),
             2 => Some(log::Level::Warn),
@@ -94,27 +192,49 @@ pub struct LogInfo {
     target: Option<String>,
 }
 
+/// Offchain worker storage. smoldot only forwards `PERSISTENT` accesses, `LOCAL` ones are
+/// no-ops as Substrate never implemented them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OffchainStorageKind {
+    Persistent,
+    Local,
+}
+
+/// Sent to `JsCallback.onLog` as soon as the runtime emits a log.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RuntimeLogEvent<'a> {
+    task_id: u32,
+    call_index: usize,
+    log: &'a LogInfo,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct CallResponse {
     result: HexString,
     storage_diff: Vec<(HexString, Option<HexString>)>,
-    offchain_storage_diff: Vec<(HexString, Option<HexString>)>,
+    offchain_indexing_diff: Vec<(HexString, Option<HexString>)>,
+    offchain_local_storage_diff: Vec<(OffchainStorageKind, HexString, Option<HexString>)>,
     runtime_logs: Vec<LogInfo>,
+    signature_checks: Vec<SignatureCheck>,
+    submitted_transactions: Vec<HexString>,
+    /// Empty unless `TaskCall::profile` is set.
+    call_profiles: Vec<CallProfile>,
+    /// Empty unless `TaskCall::trace_storage` is set.
+    storage_trace: Vec<StorageAccess>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TaskResponse {
     Call(CallResponse),
     Error(String),
+    /// Cancelled or timed out, see `TaskAbort`.
+    Aborted(TaskAborted),
 }
 
-// starts with 0xdeadbeef and then rest filled by 0xcd
-fn is_magic_signature(signature: &[u8]) -> bool {
-    signature.starts_with(&[0xde, 0xad, 0xbe, 0xef]) && signature[4..].iter().all(|&b| b == 0xcd)
-}
-
-const DEFAULT_CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:default:";
+pub const DEFAULT_CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:default:";
 
 fn prefixed_child_key(child: impl Iterator<Item = u8>, key: impl Iterator<Item = u8>) -> Vec<u8> {
     [
@@ -134,13 +254,78 @@ fn handle_value(value: wasm_bindgen::JsValue) -> Result<Option<Vec<u8>>, JsError
     }
 }
 
+/// Decodes a SCALE-encoded `Option<Vec<u8>>`.
+fn decode_scale_option_bytes(data: &[u8]) -> Option<Option<&[u8]>> {
+    match data.split_first()? {
+        (0, []) => Some(None),
+        (1, rest) => {
+            let (len, rest) = decode_scale_compact_usize(rest)?;
+            (rest.len() == len).then_some(Some(rest))
+        }
+        _ => None,
+    }
+}
+
+/// Decodes a SCALE-compact number, returning it along with the remaining bytes.
+fn decode_scale_compact_usize(data: &[u8]) -> Option<(usize, &[u8])> {
+    let first = *data.first()?;
+    let (len, value) = match first & 0b11 {
+        0b00 => (1, usize::from(first >> 2)),
+        0b01 => (
+            2,
+            usize::from(u16::from_le_bytes(data.get(..2)?.try_into().ok()?) >> 2),
+        ),
+        0b10 => (
+            4,
+            usize::try_from(u32::from_le_bytes(data.get(..4)?.try_into().ok()?) >> 2).ok()?,
+        ),
+        _ => {
+            let len = 1 + usize::from(first >> 2) + 4;
+            let mut bytes = [0u8; 8];
+            let value = data.get(1..len)?;
+            bytes.get_mut(..value.len())?.copy_from_slice(value);
+            (len, usize::try_from(u64::from_le_bytes(bytes)).ok()?)
+        }
+    };
+    Some((value, &data[len..]))
+}
+
+/// Message of a failed runtime call. Running out of memory also reports the size of the heap,
+/// smoldot doesn't expose the allocator statistics. A trap reports the panic message logged by
+/// the runtime, if any.
+fn call_error(err: runtime_call::Error, panic_message: Option<String>) -> String {
+    match err.detail {
+        host::Error::OutOfMemory { .. } => {
+            let heap_pages = u32::from(err.prototype.heap_pages());
+            format!(
+                "{}, heap pages: {heap_pages} ({} KiB)",
+                err.detail,
+                u64::from(heap_pages) * 64
+            )
+        }
+        host::Error::Trap(trap) => match panic_message {
+            Some(panic_message) => format!("{trap}, runtime {panic_message}"),
+            None => trap.to_string(),
+        },
+        detail => detail.to_string(),
+    }
+}
+
 pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskResponse, JsValue> {
     let mut storage_main_trie_changes = TrieDiff::default();
     let mut storage_changes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = Default::default();
-    let mut offchain_storage_changes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = Default::default();
-
+    let mut offchain_indexing_changes: BTreeMap<Vec<u8>, Option<Vec<u8>>> = Default::default();
+    let mut offchain_local_storage_changes: BTreeMap<
+        (OffchainStorageKind, Vec<u8>),
+        Option<Vec<u8>>,
+    > = Default::default();
+
+    let stubbed_wasm = match stub_imports_cached(&task.wasm.0, &task.import_stubs) {
+        Ok(stubbed_wasm) => stubbed_wasm,
+        Err(e) => return Ok(TaskResponse::Error(e)),
+    };
     let vm_proto = match HostVmPrototype::new(Config {
-        module: &task.wasm,
+        module: stubbed_wasm.as_deref().unwrap_or(&task.wasm.0),
         heap_pages: HeapPages::from(2048),
         exec_hint: smoldot::executor::vm::ExecHint::ValidateAndExecuteOnce,
         allow_unresolved_imports: task.allow_unresolved_imports,
@@ -149,18 +334,39 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
         Err(e) => return Ok(TaskResponse::Error(e.to_string())),
     };
 
+    let log_directives = match task.log_directives() {
+        Ok(log_directives) => log_directives,
+        Err(e) => return Ok(TaskResponse::Error(e)),
+    };
+
+    let mock_signature_policy = match MockSignaturePolicy::try_from(task.mock_signature_host) {
+        Ok(mock_signature_policy) => mock_signature_policy,
+        Err(e) => return Ok(TaskResponse::Error(e)),
+    };
+    let abort = TaskAbort::new(task.id, task.timeout);
+    // `onLog` is optional
+    let stream_logs = task.stream_logs && js.on_log_callback().is_function();
+
     let mut ret: Result<Vec<u8>, String> = Ok(Vec::new());
     let mut runtime_logs: Vec<LogInfo> = vec![];
+    let mut signature_checks: Vec<SignatureCheck> = vec![];
+    let mut random_seed_index: u64 = 0;
+    let mut submitted_transactions: Vec<HexString> = vec![];
+    let mut call_profiles: Vec<CallProfile> = vec![];
+    let mut storage_trace: Vec<StorageAccess> = vec![];
+    // values read from chopsticks, to report the old value of the writes
+    let mut backend_values: BTreeMap<Vec<u8>, Option<Vec<u8>>> = Default::default();
 
-    for (call, params) in task.calls {
-        log::trace!(target: LOG_TARGET, "[{}] Calling {call}", task.id);
+    for (call_index, (call, params)) in task.calls.into_iter().enumerate() {
+        log::trace!(target: LOG_TARGET, task_id = task.id; "[{}] Calling {call}", task.id);
 
         let vm = runtime_call::run(runtime_call::Config {
             virtual_machine: vm_proto.clone(),
             function_to_call: call.as_str(),
             parameter: params.into_iter().map(|x| x.0),
             storage_main_trie_changes,
-            max_log_level: task.runtime_log_level,
+            // error logs are always enabled to catch the panic message
+            max_log_level: log_directives.max_level().max(LevelFilter::Error) as u32,
             calculate_trie_changes: false,
             storage_proof_size_behavior:
                 runtime_call::StorageProofSizeBehavior::ConstantReturnValue(task.storage_proof_size),
@@ -174,7 +380,14 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
             }
         };
 
+        let mut profiler = Profiler::new(task.profile, &call);
+        let mut panic_message: Option<String> = None;
+
         let res = loop {
+            if let Err(aborted) = abort.check(&call) {
+                return Ok(TaskResponse::Aborted(aborted));
+            }
+            profiler.enter(&vm);
             vm = match vm {
                 RuntimeCall::Finished(res) => {
                     break res;
@@ -192,6 +405,14 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
 
                     // check storage_changes first
                     if let Some(value) = storage_changes.get(&key.0) {
+                        profiler.read(value.as_ref().map_or(0, Vec::len));
+                        if task.trace_storage {
+                            storage_trace.push(StorageAccess::Read {
+                                call_index,
+                                key: key.clone(),
+                                value: value.clone().map(HexString),
+                            });
+                        }
                         req.inject_value(
                             value
                                 .to_owned()
@@ -199,15 +420,25 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
                         )
                     } else {
                         // otherwise, ask chopsticks
+                        let traced_key = key.clone();
                         let key = to_value(&key)?;
 
-                        let value = js.get_storage(key).await?;
+                        let value = profiler.callback(js.get_storage(key)).await?;
                         let value = if value.is_string() {
                             let encoded = from_value::<HexString>(value).map(|x| x.0)?;
                             Some(encoded)
                         } else {
                             None
                         };
+                        profiler.read(value.as_ref().map_or(0, Vec::len));
+                        if task.trace_storage {
+                            backend_values.insert(traced_key.0.clone(), value.clone());
+                            storage_trace.push(StorageAccess::Read {
+                                call_index,
+                                key: traced_key,
+                                value: value.clone().map(HexString),
+                            });
+                        }
                         req.inject_value(value.map(|x| (iter::once(x), TrieEntryVersion::V1)))
                     }
                 }
@@ -237,39 +468,41 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
                         } else {
                             HexString(nibbles_to_bytes_suffix_extend(req.key()).collect::<Vec<_>>())
                         };
+                        let traced = task.trace_storage.then(|| (prefix.clone(), key.clone()));
                         let prefix = to_value(&prefix)?;
                         let key = to_value(&key)?;
-                        let value = js.get_next_key(prefix, key).await?;
-                        req.inject_key(
-                            handle_value(value)?.map(|x| bytes_to_nibbles(x.into_iter())),
-                        )
+                        let value = profiler.callback(js.get_next_key(prefix, key)).await?;
+                        let next_key = handle_value(value)?;
+                        if let Some((prefix, key)) = traced {
+                            storage_trace.push(StorageAccess::NextKey {
+                                call_index,
+                                prefix,
+                                key,
+                                next_key: next_key.clone().map(HexString),
+                            });
+                        }
+                        req.inject_key(next_key.map(|x| bytes_to_nibbles(x.into_iter())))
                     }
                 }
 
                 RuntimeCall::SignatureVerification(req) => {
-                    match task.mock_signature_host {
-                        1 => {
-                            // require magic signature
-                            let bypass = is_magic_signature(req.signature().as_ref());
-                            if bypass {
-                                req.resume_success()
-                            } else {
-                                req.verify_and_resume()
-                            }
-                        }
-                        2 => {
-                            // always valid
-                            req.resume_success()
-                        }
-                        0 | _ => {
-                            // no mock
-                            req.verify_and_resume()
-                        }
+                    let check = mock_signature_policy.check(
+                        req.public_key().as_ref(),
+                        req.message().as_ref(),
+                        req.signature().as_ref(),
+                        || req.is_valid(),
+                    );
+                    let valid = check.is_valid();
+                    signature_checks.push(check);
+                    if valid {
+                        req.resume_success()
+                    } else {
+                        req.resume_failed()
                     }
                 }
 
                 RuntimeCall::OffchainStorageSet(req) => {
-                    offchain_storage_changes.insert(
+                    offchain_indexing_changes.insert(
                         req.key().as_ref().to_vec(),
                         req.value().map(|x| x.as_ref().to_vec()),
                     );
@@ -278,25 +511,41 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
 
                 RuntimeCall::Offchain(ctx) => match ctx {
                     OffchainContext::StorageGet(req) => {
-                        let key = HexString(req.key().as_ref().to_vec());
-                        let key = to_value(&key)?;
-                        let value = js.offchain_get_storage(key).await?;
-                        req.inject_value(handle_value(value)?)
+                        let key = (OffchainStorageKind::Persistent, req.key().as_ref().to_vec());
+                        // check offchain_local_storage_changes first
+                        if let Some(value) = offchain_local_storage_changes.get(&key) {
+                            req.inject_value(value.clone())
+                        } else {
+                            let key = to_value(&HexString(key.1))?;
+                            let value = profiler.callback(js.offchain_get_storage(key)).await?;
+                            req.inject_value(handle_value(value)?)
+                        }
                     }
 
                     OffchainContext::StorageSet(req) => {
-                        let key = req.key().as_ref().to_vec();
-                        let current_value = offchain_storage_changes.get(&key);
-
-                        let replace = match (current_value, req.old_value()) {
-                            (Some(Some(current_value)), Some(old_value)) => {
-                                old_value.as_ref().eq(current_value)
+                        let key = (OffchainStorageKind::Persistent, req.key().as_ref().to_vec());
+
+                        let replace = match req.old_value() {
+                            Some(old_value) => {
+                                let old_value = decode_scale_option_bytes(old_value.as_ref())
+                                    .ok_or_else(|| JsError::new("invalid offchain old value"))?
+                                    .map(|x| x.to_vec());
+                                let current_value = match offchain_local_storage_changes.get(&key) {
+                                    Some(value) => value.clone(),
+                                    None => {
+                                        let key = to_value(&HexString(key.1.clone()))?;
+                                        let value =
+                                            profiler.callback(js.offchain_get_storage(key)).await?;
+                                        handle_value(value)?
+                                    }
+                                };
+                                current_value == old_value
                             }
-                            _ => true,
+                            None => true,
                         };
 
                         if replace {
-                            offchain_storage_changes
+                            offchain_local_storage_changes
                                 .insert(key, req.value().map(|x| x.as_ref().to_vec()));
                         }
 
@@ -304,106 +553,178 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
                     }
 
                     OffchainContext::Timestamp(req) => {
-                        let value = js.offchain_timestamp().await?;
-                        let timestamp = from_value::<u64>(value)?;
+                        let timestamp = match task.offchain_timestamp {
+                            Some(timestamp) => timestamp,
+                            None => from_value::<u64>(
+                                profiler.callback(js.offchain_timestamp()).await?,
+                            )?,
+                        };
                         req.inject_timestamp(timestamp)
                     }
 
                     OffchainContext::RandomSeed(req) => {
-                        let value = js.offchain_random_seed().await?;
-                        let random = from_value::<HexString>(value)?;
-                        let value: [u8; 32] = random
-                            .0
-                            .try_into()
-                            .map_err(|_| JsError::new("invalid random seed value"))?;
+                        let value: [u8; 32] = match &task.offchain_random_seed {
+                            Some(seed) => {
+                                // blake2_256(seed ++ index), different for every call of the task
+                                let value = blake2_256(
+                                    &[&seed.0[..], &random_seed_index.to_le_bytes()].concat(),
+                                );
+                                random_seed_index += 1;
+                                value
+                            }
+                            None => {
+                                let value = profiler.callback(js.offchain_random_seed()).await?;
+                                let random = from_value::<HexString>(value)?;
+                                random
+                                    .0
+                                    .try_into()
+                                    .map_err(|_| JsError::new("invalid random seed value"))?
+                            }
+                        };
                         req.inject_random_seed(value)
                     }
 
                     OffchainContext::SubmitTransaction(req) => {
                         let tx = HexString(req.transaction().as_ref().to_vec());
+                        submitted_transactions.push(tx.clone());
                         let tx = to_value(&tx)?;
-                        let success = js.offchain_submit_transaction(tx).await?;
+                        let success = profiler
+                            .callback(js.offchain_submit_transaction(tx))
+                            .await?;
                         let success = from_value::<bool>(success)?;
                         req.resume(success)
                     }
                 },
 
                 RuntimeCall::LogEmit(req) => {
-                    {
-                        match req.info() {
-                            LogEmitInfo::Num(v) => {
-                                log::info!("[{}] {}", task.id, v);
-                                runtime_logs.push(LogInfo {
-                                    message: format!("{}", v),
-                                    level: None,
-                                    target: None,
-                                });
-                            }
-                            LogEmitInfo::Utf8(v) => {
-                                log::info!("[{}] {}", task.id, v.to_string());
-                                runtime_logs.push(LogInfo {
-                                    message: v.to_string(),
-                                    level: None,
-                                    target: None,
-                                });
-                            }
-                            LogEmitInfo::Hex(v) => {
-                                log::info!("[{}] {}", task.id, v.to_string());
-                                runtime_logs.push(LogInfo {
-                                    message: v.to_string(),
-                                    level: None,
-                                    target: None,
-                                });
+                    let log = match req.info() {
+                        LogEmitInfo::Num(v) => {
+                            log::info!(task_id = task.id; "[{}] {}", task.id, v);
+                            Some(LogInfo {
+                                message: format!("{}", v),
+                                level: None,
+                                target: None,
+                            })
+                        }
+                        LogEmitInfo::Utf8(v) => {
+                            log::info!(task_id = task.id; "[{}] {}", task.id, v);
+                            Some(LogInfo {
+                                message: v.to_string(),
+                                level: None,
+                                target: None,
+                            })
+                        }
+                        LogEmitInfo::Hex(v) => {
+                            log::info!(task_id = task.id; "[{}] {}", task.id, v);
+                            Some(LogInfo {
+                                message: v.to_string(),
+                                level: None,
+                                target: None,
+                            })
+                        }
+                        LogEmitInfo::Log {
+                            log_level,
+                            target,
+                            message,
+                        } => {
+                            let level = match log_level {
+                                0 => log::Level::Error,
+                                1 => log::Level::Warn,
+                                2 => log::Level::Info,
+                                3 => log::Level::Debug,
+                                4 => log::Level::Trace,
+                                l => unreachable!("unexpected log level {l}"),
+                            };
+                            let message = message.to_string();
+                            if level == log::Level::Error && message.starts_with("panicked at") {
+                                panic_message = Some(message.clone());
                             }
-                            LogEmitInfo::Log {
-                                log_level,
-                                target,
-                                message,
-                            } => {
-                                let level = match log_level {
-                                    0 => log::Level::Error,
-                                    1 => log::Level::Warn,
-                                    2 => log::Level::Info,
-                                    3 => log::Level::Debug,
-                                    4 => log::Level::Trace,
-                                    l => unreachable!("unexpected log level {l}"),
-                                };
-                                log::log!(target: target.as_ref(), level, "[{}] {}", task.id, message.to_string());
-                                runtime_logs.push(LogInfo {
-                                    message: message.to_string(),
+                            if log_directives.enabled(target.as_ref(), level) {
+                                // streamed logs are printed by `onLog`
+                                if !stream_logs {
+                                    log::log!(target: target.as_ref(), level, task_id = task.id; "[{}] {}", task.id, message);
+                                }
+                                Some(LogInfo {
+                                    message,
                                     level: Some(log_level),
                                     target: Some(target.to_string()),
-                                });
+                                })
+                            } else {
+                                None
                             }
-                        };
+                        }
+                    };
+
+                    if let Some(log) = log {
+                        if stream_logs {
+                            let event = RuntimeLogEvent {
+                                task_id: task.id,
+                                call_index,
+                                log: &log,
+                            };
+                            profiler.callback(js.on_log(to_value(&event)?)).await?;
+                        }
+                        runtime_logs.push(log);
                     }
                     req.resume()
                 }
-            }
+            };
+            profiler.leave();
         };
 
-        log::trace!(target: LOG_TARGET, "[{}] Completed {call}", task.id);
+        log::trace!(target: LOG_TARGET, task_id = task.id; "[{}] Completed {call}", task.id);
 
         match res {
             Ok(success) => {
                 ret = Ok(success.virtual_machine.value().as_ref().to_vec());
 
-                success
+                let mut changes = success
                     .storage_changes
                     .storage_changes_iter_unordered()
-                    .for_each(|(child, key, value)| {
+                    .map(|(child, key, value)| {
                         let prefixed_key = if let Some(child) = child {
                             prefixed_child_key(child.iter().copied(), key.iter().copied())
                         } else {
                             key.to_vec()
                         };
-                        storage_changes.insert(prefixed_key, value.map(|x| x.to_vec()));
-                    });
+                        (prefixed_key, value.map(|x| x.to_vec()))
+                    })
+                    .collect::<Vec<_>>();
+                changes.sort();
+
+                for (key, value) in changes {
+                    profiler.write(value.as_ref().map_or(0, Vec::len));
+                    if task.trace_storage {
+                        let old_value = match storage_changes.get(&key) {
+                            Some(old_value) => old_value.clone(),
+                            None => match backend_values.get(&key) {
+                                Some(old_value) => old_value.clone(),
+                                None => {
+                                    let value = profiler
+                                        .callback(
+                                            js.get_storage(to_value(&HexString(key.clone()))?),
+                                        )
+                                        .await?;
+                                    handle_value(value)?
+                                }
+                            },
+                        };
+                        storage_trace.push(StorageAccess::Write {
+                            call_index,
+                            key: HexString(key.clone()),
+                            old_value: old_value.map(HexString),
+                            new_value: value.clone().map(HexString),
+                        });
+                    }
+                    storage_changes.insert(key, value);
+                }
 
                 storage_main_trie_changes = success.storage_changes.into_main_trie_diff();
+                call_profiles.extend(profiler.finish());
             }
             Err(err) => {
-                ret = Err(err.to_string());
+                ret = Err(call_error(err, panic_message));
+                call_profiles.extend(profiler.finish());
                 break;
             }
         }
@@ -415,16 +736,26 @@ pub async fn run_task(task: TaskCall, js: crate::JsCallback) -> Result<TaskRespo
             .map(|(k, v)| (HexString(k), v.map(HexString)))
             .collect();
 
-        let offchain_storage_diff = offchain_storage_changes
+        let offchain_indexing_diff = offchain_indexing_changes
             .into_iter()
             .map(|(k, v)| (HexString(k), v.map(HexString)))
             .collect();
 
+        let offchain_local_storage_diff = offchain_local_storage_changes
+            .into_iter()
+            .map(|((kind, k), v)| (kind, HexString(k), v.map(HexString)))
+            .collect();
+
         TaskResponse::Call(CallResponse {
             result: HexString(ret),
             storage_diff,
-            offchain_storage_diff,
+            offchain_indexing_diff,
+            offchain_local_storage_diff,
             runtime_logs,
+            signature_checks,
+            submitted_transactions,
+            call_profiles,
+            storage_trace,
         })
     }))
 }
@@ -443,58 +774,35 @@ pub async fn runtime_version(wasm: HexString) -> Result<RuntimeVersion, JsError>
     Ok(RuntimeVersion::new(core_version))
 }
 
+/// Root of the trie of `entries`. Entries of default child tries go to their own trie, like in
+/// `StateTrie`.
 pub fn calculate_state_root(
     entries: Vec<(HexString, HexString)>,
     trie_version: TrieEntryVersion,
+    hash_function: HashFunction,
 ) -> HexString {
-    let mut calc = root_merkle_value(HashFunction::Blake2);
-    let map = entries
-        .into_iter()
-        .map(|(k, v)| (k.0, v.0))
-        .collect::<BTreeMap<Vec<u8>, Vec<u8>>>();
-    loop {
-        match calc {
-            RootMerkleValueCalculation::Finished { hash, .. } => {
-                return HexString(hash.to_vec());
-            }
-            RootMerkleValueCalculation::NextKey(next_key) => {
-                let lower_bound = if next_key.or_equal() {
-                    Bound::Included(next_key.key_before().collect::<Vec<_>>())
-                } else {
-                    Bound::Excluded(next_key.key_before().collect::<Vec<_>>())
-                };
-
-                let k = map
-                    .range((lower_bound, Bound::Unbounded))
-                    .next()
-                    .filter(|(k, _)| {
-                        k.iter()
-                            .copied()
-                            .zip(next_key.prefix())
-                            .all(|(a, b)| a == b)
-                    })
-                    .map(|(k, _)| k);
-
-                calc = next_key.inject_key(k.map(|k| k.iter().copied()));
-            }
-            RootMerkleValueCalculation::StorageValue(req) => {
-                let key = req.key().collect::<Vec<u8>>();
-                calc = req.inject(map.get(&key).map(|x| (x, trie_version)));
-            }
-        }
-    }
+    let mut state_trie = StateTrie::new(
+        entries.into_iter().map(|(k, v)| (k.0, v.0)).collect(),
+        trie_version,
+        hash_function,
+    );
+    HexString(state_trie.root_hash().to_vec())
 }
 
 #[test]
-fn is_magic_signature_works() {
-    assert!(is_magic_signature(&[0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd]));
-    assert!(is_magic_signature(&[
-        0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd, 0xcd, 0xcd
-    ]));
-    assert!(!is_magic_signature(&[
-        0xde, 0xad, 0xbe, 0xef, 0xcd, 0xcd, 0xcd, 0x00
-    ]));
-    assert!(!is_magic_signature(&[
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
-    ]));
+fn decode_scale_option_bytes_works() {
+    assert_eq!(decode_scale_option_bytes(&[0]), Some(None));
+    assert_eq!(decode_scale_option_bytes(&[1, 0]), Some(Some(&[][..])));
+    assert_eq!(
+        decode_scale_option_bytes(&[1, 8, 0xaa, 0xbb]),
+        Some(Some(&[0xaa, 0xbb][..]))
+    );
+    let long = [&[1, 0x01, 0x01][..], &[0xcc; 64]].concat();
+    assert_eq!(
+        decode_scale_option_bytes(&long),
+        Some(Some(&[0xcc; 64][..]))
+    );
+    assert_eq!(decode_scale_option_bytes(&[1, 8, 0xaa]), None);
+    assert_eq!(decode_scale_option_bytes(&[2]), None);
+    assert_eq!(decode_scale_option_bytes(&[]), None);
 }
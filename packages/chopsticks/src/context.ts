@@ -53,7 +53,9 @@ export const setupContext = async (argv: Config, overrideParent = false) => {
     db: argv.db ? new SqliteDatabase(argv.db) : undefined,
     mockSignatureHost: argv['mock-signature-host'],
     allowUnresolvedImports: argv['allow-unresolved-imports'],
+    importStubs: argv['import-stubs'],
     runtimeLogLevel: argv['runtime-log-level'],
+    runtimeLogDirectives: argv['runtime-log-directives'],
     registeredTypes: argv['registered-types'],
     offchainWorker: argv['offchain-worker'],
     maxMemoryBlockCount: argv['max-memory-block-count'],
@@ -58,6 +58,7 @@ export const cli = (y: Argv) => {
           mockSignatureHost: false,
           allowUnresolvedImports: false,
           runtimeLogLevel: parsedArgv['runtime-log-level'] || 0,
+          runtimeLogDirectives: parsedArgv['runtime-log-directives'],
         },
         taskHandler(parent),
       )
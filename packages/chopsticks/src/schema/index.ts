@@ -11,6 +11,18 @@ import { ZodNativeEnum, type ZodRawShape, type ZodTypeAny, z } from 'zod'
 export const zHex = z.custom<HexString>((val: any) => /^0x\w+$/.test(val))
 export const zHash = z.string().length(66).and(zHex)
 
+const zMockSignatureSchemePolicy = z.object({
+  mode: z.enum(['none', 'magic', 'always']).optional(),
+  accounts: z.array(zHex).optional(),
+  magic: z.object({ prefix: zHex, fill: z.number().int().min(0).max(255) }).optional(),
+})
+
+const zMockSignaturePolicy = z.object({
+  sr25519: zMockSignatureSchemePolicy.optional(),
+  ed25519: zMockSignatureSchemePolicy.optional(),
+  ecdsa: zMockSignatureSchemePolicy.optional(),
+})
+
 export const configSchema = z.object({
   addr: z.union([z.literal('localhost'), z.string().ip()]).optional(),
   host: z
@@ -37,9 +49,15 @@ export const configSchema = z.object({
   'build-block-mode': z.nativeEnum(BuildBlockMode).default(BuildBlockMode.Batch),
   'import-storage': z.any({ description: 'Pre-defined JSON/YAML storage file path' }).optional(),
   'allow-unresolved-imports': z.boolean().optional(),
+  'import-stubs': z
+    .record(z.union([z.literal('zero'), z.literal('noOp'), z.object({ constant: z.number() })]), {
+      description: 'Built-in implementations of host functions the runtime imports but chopsticks lacks, by name',
+    })
+    .optional(),
   'mock-signature-host': z
-    .boolean({
-      description: 'Mock signature host so any signature starts with 0xdeadbeef and filled by 0xcd is considered valid',
+    .union([z.boolean(), zMockSignaturePolicy], {
+      description:
+        'Mock signature host so any signature starts with 0xdeadbeef and filled by 0xcd is considered valid. Can also be a policy per signature scheme',
     })
     .optional(),
   'max-memory-block-count': z.number().optional(),
@@ -66,6 +84,12 @@ export const configSchema = z.object({
     .min(0)
     .max(5)
     .optional(),
+  'runtime-log-directives': z
+    .string({
+      description:
+        'RUST_LOG-style runtime log directives, e.g. `info,runtime::evm=trace`, overriding runtime-log-level',
+    })
+    .optional(),
   'offchain-worker': z.boolean({ description: 'Enable offchain worker' }).optional(),
   resume: z
     .union([zHash, z.number(), z.boolean()], {
@@ -68,6 +68,12 @@ it('get yargs options from zod schema', () => {
         "description": "Pre-defined JSON/YAML storage file path",
         "type": undefined,
       },
+      "import-stubs": {
+        "choices": undefined,
+        "demandOption": false,
+        "description": "Built-in implementations of host functions the runtime imports but chopsticks lacks, by name",
+        "type": undefined,
+      },
       "max-memory-block-count": {
         "choices": undefined,
         "demandOption": false,
@@ -77,7 +83,7 @@ it('get yargs options from zod schema', () => {
       "mock-signature-host": {
         "choices": undefined,
         "demandOption": false,
-        "description": "Mock signature host so any signature starts with 0xdeadbeef and filled by 0xcd is considered valid",
+        "description": "Mock signature host so any signature starts with 0xdeadbeef and filled by 0xcd is considered valid. Can also be a policy per signature scheme",
         "type": "boolean",
       },
       "offchain-worker": {
@@ -122,6 +128,12 @@ it('get yargs options from zod schema', () => {
         "description": "RPC timeout in milliseconds",
         "type": "number",
       },
+      "runtime-log-directives": {
+        "choices": undefined,
+        "demandOption": false,
+        "description": "RUST_LOG-style runtime log directives, e.g. \`info,runtime::evm=trace\`, overriding runtime-log-level",
+        "type": "string",
+      },
       "runtime-log-level": {
         "choices": undefined,
         "demandOption": false,
@@ -11,10 +11,10 @@ import type {
 } from '@polkadot/types/interfaces'
 import { compactAddLength, hexToU8a, stringToHex, u8aConcat, u8aToHex } from '@polkadot/util'
 import type { HexString } from '@polkadot/util/types'
-import { blake2AsU8a } from '@polkadot/util-crypto'
+import { blake2AsU8a, decodeAddress } from '@polkadot/util-crypto'
 import { defaultLogger, truncate } from '../logger.js'
 import { compactHex, getCurrentSlot } from '../utils/index.js'
-import type { TaskCallResponse } from '../wasm-executor/index.js'
+import { mockSignatureAccepts, type TaskCallResponse } from '../wasm-executor/index.js'
 import { Block } from './block.js'
 import type { InherentProvider } from './inherent/index.js'
 import { StorageLayer, StorageValueKind } from './storage-layer.js'
@@ -430,9 +430,14 @@ export const dryRunExtrinsic = async (
   const { block: newBlock } = await initNewBlock(head, header, inherentProviders, params)
 
   if (typeof extrinsic !== 'string') {
-    if (!head.chain.mockSignatureHost) {
+    const mockSignature = new Uint8Array(64)
+    mockSignature.fill(0xcd)
+    mockSignature.set([0xde, 0xad, 0xbe, 0xef])
+
+    const publicKey = u8aToHex(decodeAddress(extrinsic.address))
+    if (!mockSignatureAccepts(head.chain.mockSignatureHost, publicKey, mockSignature)) {
       throw new Error(
-        'Cannot fake signature because mock signature host is not enabled. Start chain with `mockSignatureHost: true`',
+        'Cannot fake signature because mock signature host does not accept it. Start chain with `mockSignatureHost: true` or a policy accepting the 0xdeadbeef signature of this account',
       )
     }
 
@@ -450,9 +455,6 @@ export const dryRunExtrinsic = async (
       nonce: account.nonce,
     })
 
-    const mockSignature = new Uint8Array(64)
-    mockSignature.fill(0xcd)
-    mockSignature.set([0xde, 0xad, 0xbe, 0xef])
     generic.signature.set(mockSignature)
 
     logger.debug({ call: call.toHuman() }, 'dry_run_call')
@@ -322,7 +322,9 @@ export class Block {
         calls: [[method, args]],
         mockSignatureHost: this.#chain.mockSignatureHost,
         allowUnresolvedImports: this.#chain.allowUnresolvedImports,
+        importStubs: this.#chain.importStubs,
         runtimeLogLevel: this.#chain.runtimeLogLevel,
+        runtimeLogDirectives: this.#chain.runtimeLogDirectives,
       },
       taskHandler(this),
       mockSigantureHostOverride,
@@ -330,7 +332,10 @@ export class Block {
     if ('Call' in response) {
       if (this.chain.offchainWorker) {
         // apply offchain storage
-        for (const [key, value] of response.Call.offchainStorageDiff) {
+        for (const [key, value] of response.Call.offchainIndexingDiff) {
+          this.chain.offchainWorker.set(key, value)
+        }
+        for (const [_kind, key, value] of response.Call.offchainLocalStorageDiff) {
           this.chain.offchainWorker.set(key, value)
         }
       }
@@ -14,7 +14,7 @@ import type { Database } from '../database.js'
 import { defaultLogger } from '../logger.js'
 import { OffchainWorker } from '../offchain.js'
 import { compactHex } from '../utils/index.js'
-import type { RuntimeVersion } from '../wasm-executor/index.js'
+import type { ImportStub, MockSignaturePolicy, RuntimeVersion } from '../wasm-executor/index.js'
 import { Block } from './block.js'
 import { dryRunExtrinsic, dryRunExtrinsicsAmortized, dryRunInherents } from './block-builder.js'
 import { HeadState } from './head-state.js'
@@ -41,12 +41,16 @@ export interface Options {
   db?: Database
   /** Used to create the initial head. */
   header: { number: number; hash: HexString }
-  /** Whether to enable mock signature. Any signature starts with 0xdeadbeef and filled by 0xcd is considered valid */
-  mockSignatureHost?: boolean
+  /** Whether to enable mock signature. Any signature starts with 0xdeadbeef and filled by 0xcd is considered valid. Can also be a policy per signature scheme */
+  mockSignatureHost?: boolean | MockSignaturePolicy
   /** Whether to allow wasm unresolved imports. */
   allowUnresolvedImports?: boolean
+  /** Built-in implementations of host functions the runtime imports but the executor lacks, by name. */
+  importStubs?: Record<string, ImportStub>
   /** Wasm runtime log level. */
   runtimeLogLevel?: number
+  /** `RUST_LOG`-style runtime log directives, e.g. `info,runtime::evm=trace`, overriding `runtimeLogLevel`. */
+  runtimeLogDirectives?: string
   /** Polkadot.js custom types registration. */
   registeredTypes: RegisteredTypes
   /** Whether to enable offchain Worker. */
@@ -88,10 +92,14 @@ export class Blockchain {
   /** Datasource for caching storage and blocks data. */
   readonly db: Database | undefined
   /** Enable mock signature. Any signature starts with 0xdeadbeef and filled by 0xcd is considered valid */
-  readonly mockSignatureHost: boolean
+  readonly mockSignatureHost: boolean | MockSignaturePolicy
   /** Allow wasm unresolved imports. */
   readonly allowUnresolvedImports: boolean
+  /** Built-in implementations of host functions the runtime imports but the executor lacks. */
+  readonly importStubs: Record<string, ImportStub>
   #runtimeLogLevel: number
+  /** Runtime log directives overriding the runtime log level, cleared when the level is set. */
+  runtimeLogDirectives: string | undefined
   /** Polkadot.js custom types registration. */
   readonly registeredTypes: RegisteredTypes
 
@@ -143,7 +151,9 @@ export class Blockchain {
     header,
     mockSignatureHost = false,
     allowUnresolvedImports = false,
+    importStubs = {},
     runtimeLogLevel = 0,
+    runtimeLogDirectives,
     registeredTypes = {},
     offchainWorker = false,
     maxMemoryBlockCount = 500,
@@ -154,7 +164,9 @@ export class Blockchain {
     this.db = db
     this.mockSignatureHost = mockSignatureHost
     this.allowUnresolvedImports = allowUnresolvedImports
+    this.importStubs = importStubs
     this.#runtimeLogLevel = runtimeLogLevel
+    this.runtimeLogDirectives = runtimeLogDirectives
     this.registeredTypes = registeredTypes
 
     this.#head = new Block(this, header.number, header.hash)
@@ -200,6 +212,7 @@ export class Blockchain {
 
   set runtimeLogLevel(level: number) {
     this.#runtimeLogLevel = level
+    this.runtimeLogDirectives = undefined
     logger.debug(`Runtime log level set to ${logger.level}`)
   }
 
@@ -11,6 +11,7 @@ import type { BuildBlockMode } from './blockchain/txpool.js'
 import type { Database } from './database.js'
 import type { GenesisProvider } from './genesis-provider.js'
 import { defaultLogger } from './logger.js'
+import type { ImportStub, MockSignaturePolicy } from './wasm-executor/index.js'
 
 export type SetupOptions = {
   endpoint?: string | string[]
@@ -18,9 +19,11 @@ export type SetupOptions = {
   genesis?: GenesisProvider
   buildBlockMode?: BuildBlockMode
   db?: Database
-  mockSignatureHost?: boolean
+  mockSignatureHost?: boolean | MockSignaturePolicy
   allowUnresolvedImports?: boolean
+  importStubs?: Record<string, ImportStub>
   runtimeLogLevel?: number
+  runtimeLogDirectives?: string
   registeredTypes?: RegisteredTypes
   offchainWorker?: boolean
   maxMemoryBlockCount?: number
@@ -104,7 +107,9 @@ export const setup = async (options: SetupOptions) => {
     },
     mockSignatureHost: opts.mockSignatureHost,
     allowUnresolvedImports: opts.allowUnresolvedImports,
+    importStubs: opts.importStubs,
     runtimeLogLevel: opts.runtimeLogLevel,
+    runtimeLogDirectives: opts.runtimeLogDirectives,
     registeredTypes: opts.registeredTypes || {},
     offchainWorker: opts.offchainWorker,
     maxMemoryBlockCount: opts.maxMemoryBlockCount,
@@ -4,7 +4,7 @@ import { TypeRegistry } from '@polkadot/types'
 import type { HexString } from '@polkadot/util/types'
 import * as Comlink from 'comlink'
 import _ from 'lodash'
-import { describe, expect, it } from 'vitest'
+import { describe, expect, it, vi } from 'vitest'
 
 import { upgradeGoAheadSignal, WELL_KNOWN_KEYS } from '../utils/proof.js'
 import {
@@ -12,9 +12,13 @@ import {
   createProof,
   decodeProof,
   emptyTaskHandler,
+  type ExecutorLogRecord,
   getAuraSlotDuration,
   getRuntimeVersion,
   getWorker,
+  runTask,
+  setExecutorLogSink,
+  TaskAbortedError,
 } from './index.js'
 
 const getCode = _.memoize(() => {
@@ -160,4 +164,79 @@ describe('wasm', () => {
     const slotDuration = await getAuraSlotDuration(getCode())
     expect(slotDuration).eq(12000)
   })
+
+  it('forwards executor logs to the log sink', async () => {
+    const records: ExecutorLogRecord[] = []
+    await setExecutorLogSink((record) => {
+      records.push(record)
+    })
+    try {
+      const result = await runTask({
+        wasm: getCode(),
+        calls: [['AuraApi_slot_duration', []]],
+        mockSignatureHost: false,
+        allowUnresolvedImports: false,
+        runtimeLogLevel: 5,
+      })
+      expect('Call' in result).toBe(true)
+
+      await vi.waitFor(() =>
+        expect(records).toContainEqual(
+          expect.objectContaining({
+            level: 'trace',
+            target: 'chopsticks::executor',
+            message: expect.stringContaining('Calling AuraApi_slot_duration'),
+            taskId: expect.any(Number),
+          }),
+        ),
+      )
+    } finally {
+      await setExecutorLogSink()
+    }
+  })
+
+  it('aborts cancelled and timed out tasks', async () => {
+    const task = {
+      wasm: getCode(),
+      calls: [['AuraApi_authorities', []]] as [string, HexString[]][],
+      mockSignatureHost: false,
+      allowUnresolvedImports: false,
+      runtimeLogLevel: 0,
+    }
+    const controller = new AbortController()
+    const cancelled = runTask(
+      task,
+      {
+        ...emptyTaskHandler,
+        getStorage: async () => {
+          controller.abort()
+          // let the worker receive the cancellation before the storage value
+          await new Promise((resolve) => setTimeout(resolve, 20))
+          return undefined
+        },
+      },
+      false,
+      controller.signal,
+    )
+    await expect(cancelled).rejects.toThrowError(TaskAbortedError)
+    await expect(cancelled).rejects.toMatchObject({ reason: 'cancelled', call: 'AuraApi_authorities' })
+
+    const timedOut = runTask(
+      { ...task, timeout: 10 },
+      {
+        ...emptyTaskHandler,
+        getStorage: async () => {
+          await new Promise((resolve) => setTimeout(resolve, 20))
+          return undefined
+        },
+      },
+    )
+    await expect(timedOut).rejects.toMatchObject({ reason: 'timedOut', call: 'AuraApi_authorities' })
+
+    // cancelling a finished task doesn't affect later tasks
+    const worker = await getWorker()
+    await worker.remote.cancelTask(0)
+    const result = await runTask({ ...task, calls: [['AuraApi_slot_duration', []]] })
+    expect('Call' in result).toBe(true)
+  })
 })
@@ -20,13 +20,84 @@ export type RuntimeVersion = {
   stateVersion: number
 }
 
+// 0 for Blake2 (the default), 1 for Keccak-256
+export type HashFunction = 0 | 1
+
+export type MockSignatureSchemePolicy = {
+  mode?: 'none' | 'magic' | 'always'
+  // public keys the mock applies to, every key if not set
+  accounts?: HexString[]
+  // signature starting with `prefix` and then filled with `fill`, defaults to 0xdeadbeef and 0xcd
+  magic?: { prefix: HexString; fill: number }
+}
+
+// sr25519 and ed25519 public keys can't be told apart, so a policy accepting every signature of
+// every account of one of them must also do so for the other one
+export type MockSignaturePolicy = {
+  sr25519?: MockSignatureSchemePolicy
+  ed25519?: MockSignatureSchemePolicy
+  ecdsa?: MockSignatureSchemePolicy
+}
+
+// whether the executor mocks `signature` of `publicKey` under `mockSignatureHost`
+export const mockSignatureAccepts = (
+  mockSignatureHost: boolean | MockSignaturePolicy,
+  publicKey: HexString,
+  signature: Uint8Array,
+) => {
+  if (typeof mockSignatureHost === 'boolean') {
+    return mockSignatureHost
+  }
+  // 33 bytes for ecdsa, either sr25519 or ed25519 otherwise
+  const policies =
+    hexToU8a(publicKey).length === 33
+      ? [mockSignatureHost.ecdsa]
+      : [mockSignatureHost.sr25519, mockSignatureHost.ed25519]
+  return policies.some((policy) => {
+    if (!policy?.mode || policy.mode === 'none') return false
+    if (policy.accounts && !policy.accounts.some((account) => account.toLowerCase() === publicKey.toLowerCase())) {
+      return false
+    }
+    if (policy.mode === 'always') return true
+    const prefix = hexToU8a(policy.magic?.prefix ?? '0xdeadbeef')
+    const fill = policy.magic?.fill ?? 0xcd
+    return (
+      prefix.every((byte, i) => signature[i] === byte) &&
+      signature.subarray(prefix.length).every((byte) => byte === fill)
+    )
+  })
+}
+
+// built-in implementation of a host function the runtime imports but the executor lacks
+export type ImportStub = 'zero' | 'noOp' | { constant: number }
+
 export type TaskCall = {
   wasm: HexString
   calls: [string, HexString[]][]
-  mockSignatureHost: boolean
+  mockSignatureHost: boolean | MockSignaturePolicy
   allowUnresolvedImports: boolean
   runtimeLogLevel: number
+  runtimeLogDirectives?: string
   storageProofSize?: number
+  offchainTimestamp?: number
+  offchainRandomSeed?: HexString
+  profile?: boolean
+  traceStorage?: boolean
+  timeout?: number // milliseconds
+  importStubs?: Record<string, ImportStub> // by host function name
+  streamLogs?: boolean // send each runtime log to `callback.onLog` as it is emitted, instead of the executor log
+}
+
+// keystore and HTTP host functions are not available to the offchain worker
+export type OffchainWorkerCall = {
+  wasm: HexString
+  header: HexString // SCALE-encoded header of the block
+  runtimeLogLevel?: number
+  runtimeLogDirectives?: string
+  offchainTimestamp?: number
+  offchainRandomSeed?: HexString
+  timeout?: number // milliseconds
+  streamLogs?: boolean
 }
 
 export type RuntimeLog = {
@@ -35,11 +106,58 @@ export type RuntimeLog = {
   target?: string
 }
 
+export type ProofStats = {
+  nodeCount: number
+  totalBytes: number
+  keys: {
+    key: HexString
+    nodeCount: number
+    bytes: number
+    exclusiveBytes: number // bytes of the nodes that no other key of the proof needs
+  }[]
+}
+
 export type TaskCallResponse = {
   result: HexString
   storageDiff: [HexString, HexString | null][]
-  offchainStorageDiff: [HexString, HexString | null][]
+  offchainIndexingDiff: [HexString, HexString | null][]
+  offchainLocalStorageDiff: ['PERSISTENT' | 'LOCAL', HexString, HexString | null][]
   runtimeLogs: RuntimeLog[]
+  signatureChecks: {
+    scheme: 'sr25519' | 'ed25519' | 'ecdsa' | null // null for a verified 32-byte key, sr25519 or ed25519
+    publicKey: HexString
+    messageHash: HexString // blake2-256 of the signed message
+    magic: 'noMatch' | 'prefixOnly' | 'match'
+    outcome: 'mocked' | 'verifiedTrue' | 'verifiedFalse'
+  }[]
+  submittedTransactions: HexString[]
+  callProfiles: {
+    call: string
+    wasmTime: number
+    callbackTime: number
+    hostFunctions: [string, { count: number; time: number }][]
+    bytesRead: number
+    bytesWritten: number
+  }[]
+  storageTrace: (
+    | { type: 'read'; callIndex: number; key: HexString; value: HexString | null }
+    | { type: 'nextKey'; callIndex: number; prefix: HexString; key: HexString; nextKey: HexString | null }
+    | { type: 'write'; callIndex: number; key: HexString; oldValue: HexString | null; newValue: HexString | null }
+  )[]
+}
+
+export type ExecutorLogRecord = {
+  level: 'error' | 'warn' | 'info' | 'debug' | 'trace'
+  target: string
+  message: string
+  taskId?: number
+}
+
+export type TaskAborted = {
+  reason: 'cancelled' | 'timedOut'
+  call: string
+  elapsed: number // milliseconds
+  message: string
 }
 
 export type TaskResponse =
@@ -49,26 +167,105 @@ export type TaskResponse =
   | {
       Error: string
     }
+  | {
+      Aborted: TaskAborted
+    }
+
+// thrown by `runTask` and `runOffchainWorker` when the task is cancelled or times out
+export class TaskAbortedError extends Error {
+  reason: TaskAborted['reason']
+  call: string
+  elapsed: number
+
+  constructor({ reason, call, elapsed, message }: TaskAborted) {
+    super(message)
+    this.name = 'TaskAbortedError'
+    this.reason = reason
+    this.call = call
+    this.elapsed = elapsed
+  }
+}
 
 export interface WasmExecutor {
   getRuntimeVersion: (code: HexString) => Promise<RuntimeVersion>
-  calculateStateRoot: (entries: [HexString, HexString][], trie_version: number) => Promise<HexString>
-  createProof: (nodes: HexString[], updates: [HexString, HexString | null][]) => Promise<[HexString, HexString[]]>
-  decodeProof: (trieRootHash: HexString, nodes: HexString[]) => Promise<[[HexString, HexString]]>
+  calculateStateRoot: (
+    entries: [HexString, HexString][],
+    trie_version: number,
+    hashFunction?: HashFunction,
+  ) => Promise<HexString>
+  createProof: (
+    nodes: HexString[],
+    updates: [HexString, HexString | null][],
+    hashFunction?: HashFunction,
+  ) => Promise<[HexString, HexString[]]>
+  decodeProof: (
+    trieRootHash: HexString,
+    nodes: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<[[HexString, HexString]]>
+  encodeCompactProof: (
+    trieRootHash: HexString,
+    nodes: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<HexString[]>
+  compactProofToNodes: (
+    trieRootHash: HexString,
+    compact: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<HexString[]>
+  decodeCompactProof: (
+    trieRootHash: HexString,
+    compact: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<[HexString, HexString][]>
+  mergeProofs: (trieRootHash: HexString, proofs: HexString[][], hashFunction?: HashFunction) => Promise<HexString[]>
+  pruneProof: (
+    trieRootHash: HexString,
+    nodes: HexString[],
+    keys: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<HexString[]>
+  proofStats: (
+    trieRootHash: HexString,
+    nodes: HexString[],
+    keys?: HexString[],
+    hashFunction?: HashFunction,
+  ) => Promise<ProofStats>
+  createStateTrie: (
+    entries: [HexString, HexString][],
+    trie_version: number,
+    hashFunction?: HashFunction,
+  ) => Promise<number>
+  updateStateTrie: (id: number, diff: [HexString, HexString | null][]) => Promise<HexString>
+  deleteStateTrie: (id: number) => Promise<boolean>
+  trieNodes: (
+    entries: [HexString, HexString][],
+    trie_version: number,
+    hashFunction?: HashFunction,
+  ) => Promise<{ root: HexString; nodes: [HexString, HexString][] }>
   runTask: (
     task: {
       wasm: HexString
       calls: [string, HexString[]][]
-      mockSignatureHost: number // 0 - no mock, 1 - require magic signature, 2 - always valid
+      mockSignatureHost: number | MockSignaturePolicy // 0 - no mock, 1 - require magic signature, 2 - always valid
       allowUnresolvedImports: boolean
       runtimeLogLevel: number
     },
     callback?: JsCallback,
   ) => Promise<TaskResponse>
+  runOffchainWorker: (call: OffchainWorkerCall & { id: number }, callback?: JsCallback) => Promise<TaskResponse>
+  cancelTask: (taskId: number) => Promise<void>
+  setLogSink: (sink?: (record: ExecutorLogRecord) => void, directives?: string) => Promise<void>
   testing: (callback: JsCallback, key: any) => Promise<any>
 }
 
 const logger = defaultLogger.child({ name: 'executor' })
+const runtimeLogger = defaultLogger.child({ name: 'runtime' })
+const runtimeLogLevels = ['error', 'warn', 'info', 'debug', 'trace'] as const
+
+const logExecutorRecord = ({ level, target, message, taskId }: ExecutorLogRecord) => {
+  logger[level]({ target, taskId }, message)
+}
 
 let __executor_worker: Promise<{ remote: Comlink.Remote<WasmExecutor>; terminate: () => Promise<void> }> | undefined
 export const getWorker = async () => {
@@ -77,13 +274,26 @@ export const getWorker = async () => {
   const isNode = typeof process !== 'undefined' && process?.versions?.node // true for node or bun
 
   if (isNode) {
-    __executor_worker = import('./node-worker.js').then(({ startWorker }) => startWorker())
+    __executor_worker = import('./node-worker.js').then(({ startWorker }) => startWorker<WasmExecutor>())
   } else {
-    __executor_worker = import('./browser-worker.js').then(({ startWorker }) => startWorker())
+    __executor_worker = import('./browser-worker.js').then(({ startWorker }) => startWorker<WasmExecutor>())
   }
+  __executor_worker = __executor_worker.then(async (worker) => {
+    await worker.remote.setLogSink(Comlink.proxy(logExecutorRecord))
+    return worker
+  })
   return __executor_worker
 }
 
+// executor logs go to `sink`, or to the executor logger if not set
+export const setExecutorLogSink = async (
+  sink: (record: ExecutorLogRecord) => void = logExecutorRecord,
+  directives?: string,
+) => {
+  const worker = await getWorker()
+  await worker.remote.setLogSink(Comlink.proxy(sink), directives)
+}
+
 export const getRuntimeVersion = _.memoize(async (code: HexString): Promise<RuntimeVersion> => {
   const worker = await getWorker()
   return worker.remote.getRuntimeVersion(code).then((version) => {
@@ -97,14 +307,15 @@ export const getRuntimeVersion = _.memoize(async (code: HexString): Promise<Runt
 export const calculateStateRoot = async (
   entries: [HexString, HexString][],
   trie_version: number,
+  hashFunction: HashFunction = 0,
 ): Promise<HexString> => {
   const worker = await getWorker()
-  return worker.remote.calculateStateRoot(entries, trie_version)
+  return worker.remote.calculateStateRoot(entries, trie_version, hashFunction)
 }
 
-export const decodeProof = async (trieRootHash: HexString, nodes: HexString[]) => {
+export const decodeProof = async (trieRootHash: HexString, nodes: HexString[], hashFunction: HashFunction = 0) => {
   const worker = await getWorker()
-  const result = await worker.remote.decodeProof(trieRootHash, nodes)
+  const result = await worker.remote.decodeProof(trieRootHash, nodes, hashFunction)
   return result.reduce(
     (accum, [key, value]) => {
       accum[key] = value
@@ -114,35 +325,174 @@ export const decodeProof = async (trieRootHash: HexString, nodes: HexString[]) =
   )
 }
 
-export const createProof = async (nodes: HexString[], updates: [HexString, HexString | null][]) => {
+export const createProof = async (
+  nodes: HexString[],
+  updates: [HexString, HexString | null][],
+  hashFunction: HashFunction = 0,
+) => {
   const worker = await getWorker()
-  const [trieRootHash, newNodes] = await worker.remote.createProof(nodes, updates)
+  const [trieRootHash, newNodes] = await worker.remote.createProof(nodes, updates, hashFunction)
   return { trieRootHash, nodes: newNodes }
 }
 
+// compact proofs use the `CompactProof` encoding of Substrate and parachain PoVs
+export const encodeCompactProof = async (
+  trieRootHash: HexString,
+  nodes: HexString[],
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  return worker.remote.encodeCompactProof(trieRootHash, nodes, hashFunction)
+}
+
+export const compactProofToNodes = async (
+  trieRootHash: HexString,
+  compact: HexString[],
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  return worker.remote.compactProofToNodes(trieRootHash, compact, hashFunction)
+}
+
+export const decodeCompactProof = async (
+  trieRootHash: HexString,
+  compact: HexString[],
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  const result = await worker.remote.decodeCompactProof(trieRootHash, compact, hashFunction)
+  return result.reduce(
+    (accum, [key, value]) => {
+      accum[key] = value
+      return accum
+    },
+    {} as { [key: HexString]: HexString },
+  )
+}
+
+export const mergeProofs = async (trieRootHash: HexString, proofs: HexString[][], hashFunction: HashFunction = 0) => {
+  const worker = await getWorker()
+  return worker.remote.mergeProofs(trieRootHash, proofs, hashFunction)
+}
+
+export const pruneProof = async (
+  trieRootHash: HexString,
+  nodes: HexString[],
+  keys: HexString[],
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  return worker.remote.pruneProof(trieRootHash, nodes, keys, hashFunction)
+}
+
+// keys default to every key of the proof
+export const proofStats = async (
+  trieRootHash: HexString,
+  nodes: HexString[],
+  keys?: HexString[],
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  return worker.remote.proofStats(trieRootHash, nodes, keys, hashFunction)
+}
+
+// keeps the trie in the worker so the state root can be updated incrementally, returns the trie id
+export const createStateTrie = async (
+  entries: [HexString, HexString][],
+  trie_version: number,
+  hashFunction: HashFunction = 0,
+): Promise<number> => {
+  const worker = await getWorker()
+  return worker.remote.createStateTrie(entries, trie_version, hashFunction)
+}
+
+// applies the diff to the trie and returns its new state root
+export const updateStateTrie = async (id: number, diff: [HexString, HexString | null][]): Promise<HexString> => {
+  const worker = await getWorker()
+  return worker.remote.updateStateTrie(id, diff)
+}
+
+export const deleteStateTrie = async (id: number): Promise<boolean> => {
+  const worker = await getWorker()
+  return worker.remote.deleteStateTrie(id)
+}
+
+// returns the state root and every node of the trie, keyed by node hash
+export const trieNodes = async (
+  entries: [HexString, HexString][],
+  trie_version: number,
+  hashFunction: HashFunction = 0,
+) => {
+  const worker = await getWorker()
+  return worker.remote.trieNodes(entries, trie_version, hashFunction)
+}
+
 let nextTaskId = 0
 
 export const runTask = async (
   task: TaskCall,
   callback: JsCallback = emptyTaskHandler,
   overrideMockSignatureHost = false,
+  signal?: AbortSignal,
 ) => {
   const taskId = nextTaskId++
   const task2 = {
     ...task,
     id: taskId,
     storageProofSize: task.storageProofSize ?? 0,
-    mockSignatureHost: overrideMockSignatureHost ? 2 : task.mockSignatureHost ? 1 : 0,
+    // the worker sees a proxy, it can't tell whether `onLog` is set
+    streamLogs: !!task.streamLogs && !!callback.onLog,
+    mockSignatureHost: overrideMockSignatureHost
+      ? 2
+      : typeof task.mockSignatureHost === 'object'
+        ? task.mockSignatureHost
+        : task.mockSignatureHost
+          ? 1
+          : 0,
   }
   const worker = await getWorker()
   logger.trace(truncate(task2), `runTask #${taskId}`)
 
-  const response = await worker.remote.runTask(task2, Comlink.proxy(callback))
+  const cancel = () => {
+    worker.remote.cancelTask(taskId).catch((error) => logger.warn({ error }, `cancelTask #${taskId}`))
+  }
+  signal?.addEventListener('abort', cancel)
+  const response = await worker.remote.runTask(task2, Comlink.proxy(callback)).finally(() => {
+    signal?.removeEventListener('abort', cancel)
+  })
   if ('Call' in response) {
     logger.trace(truncate(response.Call), `taskResponse #${taskId}`)
   } else {
     logger.trace({ response }, `taskResponse ${taskId}`)
   }
+  if ('Aborted' in response) throw new TaskAbortedError(response.Aborted)
+  return response
+}
+
+// runs `OffchainWorkerApi_offchain_worker` at the block of `call.header`
+export const runOffchainWorker = async (
+  call: OffchainWorkerCall,
+  callback: JsCallback = emptyTaskHandler,
+  signal?: AbortSignal,
+) => {
+  const taskId = nextTaskId++
+  const call2 = { ...call, id: taskId, streamLogs: !!call.streamLogs && !!callback.onLog }
+  const worker = await getWorker()
+  logger.trace(truncate(call2), `runOffchainWorker #${taskId}`)
+
+  const cancel = () => {
+    worker.remote.cancelTask(taskId).catch((error) => logger.warn({ error }, `cancelTask #${taskId}`))
+  }
+  signal?.addEventListener('abort', cancel)
+  const response = await worker.remote.runOffchainWorker(call2, Comlink.proxy(callback)).finally(() => {
+    signal?.removeEventListener('abort', cancel)
+  })
+  if ('Call' in response) {
+    logger.trace(truncate(response.Call), `taskResponse #${taskId}`)
+  } else {
+    logger.trace({ response }, `taskResponse ${taskId}`)
+  }
+  if ('Aborted' in response) throw new TaskAbortedError(response.Aborted)
   return response
 }
 
@@ -174,6 +524,10 @@ export const taskHandler = (block: Block): JsCallback => {
         return false
       }
     },
+    onLog: async ({ taskId, callIndex, log }) => {
+      const level = runtimeLogLevels[log.level ?? 2] ?? 'trace'
+      runtimeLogger[level]({ taskId, callIndex, target: log.target }, log.message)
+    },
   }
 }
 
@@ -196,6 +550,7 @@ export const emptyTaskHandler = {
   offchainSubmitTransaction: async (_tx: HexString) => {
     throw new Error('Method not implemented')
   },
+  onLog: async (_event: { taskId: number; callIndex: number; log: RuntimeLog }) => {},
 }
 
 export const getAuraSlotDuration = _.memoize(async (wasm: HexString): Promise<number> => {
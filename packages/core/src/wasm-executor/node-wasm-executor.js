@@ -8,26 +8,101 @@ const getRuntimeVersion = async (code) => {
 }
 
 // trie_version: 0 for old trie, 1 for new trie
-const calculateStateRoot = async (entries, trie_version) => {
-  return pkg.calculate_state_root(entries, trie_version)
+// hash_function: 0 for Blake2, 1 for Keccak-256
+const calculateStateRoot = async (entries, trie_version, hash_function) => {
+  return pkg.calculate_state_root(entries, trie_version, hash_function)
 }
 
-const decodeProof = async (trieRootHash, nodes) => {
-  return pkg.decode_proof(trieRootHash, nodes)
+const decodeProof = async (trieRootHash, nodes, hashFunction) => {
+  return pkg.decode_proof(trieRootHash, nodes, hashFunction)
 }
 
-const createProof = async (nodes, updates) => {
-  return pkg.create_proof(nodes, updates)
+const createProof = async (nodes, updates, hashFunction) => {
+  return pkg.create_proof(nodes, updates, hashFunction)
+}
+
+const encodeCompactProof = async (trieRootHash, nodes, hashFunction) => {
+  return pkg.encode_compact_proof(trieRootHash, nodes, hashFunction)
+}
+
+const compactProofToNodes = async (trieRootHash, compact, hashFunction) => {
+  return pkg.compact_proof_to_nodes(trieRootHash, compact, hashFunction)
+}
+
+const decodeCompactProof = async (trieRootHash, compact, hashFunction) => {
+  return pkg.decode_compact_proof(trieRootHash, compact, hashFunction)
+}
+
+const mergeProofs = async (trieRootHash, proofs, hashFunction) => {
+  return pkg.merge_proofs(trieRootHash, proofs, hashFunction)
+}
+
+const pruneProof = async (trieRootHash, nodes, keys, hashFunction) => {
+  return pkg.prune_proof(trieRootHash, nodes, keys, hashFunction)
+}
+
+const proofStats = async (trieRootHash, nodes, keys, hashFunction) => {
+  return pkg.proof_stats(trieRootHash, nodes, keys, hashFunction)
+}
+
+const createStateTrie = async (entries, trie_version, hash_function) => {
+  return pkg.create_state_trie(entries, trie_version, hash_function)
+}
+
+const updateStateTrie = async (id, diff) => {
+  return pkg.update_state_trie(id, diff)
+}
+
+const deleteStateTrie = async (id) => {
+  return pkg.delete_state_trie(id)
+}
+
+const trieNodes = async (entries, trie_version, hash_function) => {
+  return pkg.trie_nodes(entries, trie_version, hash_function)
 }
 
 const runTask = async (task, callback) => {
   return pkg.run_task(task, callback)
 }
 
+const runOffchainWorker = async (call, callback) => {
+  return pkg.run_offchain_worker(call, callback)
+}
+
+const cancelTask = async (taskId) => {
+  return pkg.cancel_task(taskId)
+}
+
+// sink: function receiving { level, target, message, taskId } records, console if not set
+// directives: optional RUST_LOG-style filter
+const setLogSink = async (sink, directives) => {
+  return pkg.set_log_sink(sink, directives)
+}
+
 const testing = async (callback, key) => {
   return pkg.testing(callback, key)
 }
 
-const wasmExecutor = { runTask, getRuntimeVersion, calculateStateRoot, createProof, decodeProof, testing }
+const wasmExecutor = {
+  runTask,
+  runOffchainWorker,
+  cancelTask,
+  getRuntimeVersion,
+  calculateStateRoot,
+  createProof,
+  decodeProof,
+  encodeCompactProof,
+  compactProofToNodes,
+  decodeCompactProof,
+  mergeProofs,
+  pruneProof,
+  proofStats,
+  createStateTrie,
+  updateStateTrie,
+  deleteStateTrie,
+  trieNodes,
+  setLogSink,
+  testing,
+}
 
 Comlink.expose(wasmExecutor, nodeEndpoint(parentPort))